@@ -47,7 +47,7 @@ fn init() -> bool {
 ///
 /// Returns None if the filter name or config is determined to be invalid by each filter's `new` function.
 fn new_http_filter_config_fn<EC: EnvoyHttpFilterConfig, EHF: EnvoyHttpFilter>(
-    _envoy_filter_config: &mut EC,
+    envoy_filter_config: &mut EC,
     filter_name: &str,
     filter_config: &[u8],
 ) -> Option<Box<dyn HttpFilterConfig<EHF>>> {
@@ -60,8 +60,12 @@ fn new_http_filter_config_fn<EC: EnvoyHttpFilterConfig, EHF: EnvoyHttpFilter>(
     };
     envoy_log_trace!("new_http_filter_config_fn: filter_config: {filter_config}");
     match filter_name {
-        "http_simple_mutations" => http_simple_mutations::FilterConfig::new(filter_config)
-            .map(|config| Box::new(config) as Box<dyn HttpFilterConfig<EHF>>),
+        "http_simple_mutations" => {
+            http_simple_mutations::FilterConfig::new(filter_config).map(|mut config| {
+                config.define_counters(envoy_filter_config);
+                Box::new(config) as Box<dyn HttpFilterConfig<EHF>>
+            })
+        }
         _ => panic!(
             "Unknown filter name: {}, known filters are {}",
             filter_name, "http_simple_mutations"
@@ -0,0 +1,121 @@
+use serde_json::Value as JsonValue;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+
+// Parses an application/x-www-form-urlencoded body into a map. A key repeated in the body
+// maps to the list of its values in order. A pair that can't be decoded, e.g. with an
+// invalid percent escape or that is not valid UTF-8 once decoded, is skipped so a single
+// malformed field doesn't fail the whole request.
+pub(crate) fn parse_form_urlencoded(body: &[u8]) -> BTreeMap<String, JsonValue> {
+    let mut form = BTreeMap::new();
+    for pair in body.split(|b| *b == b'&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.iter().position(|b| *b == b'=') {
+            Some(i) => (&pair[..i], &pair[i + 1..]),
+            None => (pair, &pair[pair.len()..]),
+        };
+        let (Some(key), Some(value)) = (percent_decode(key), percent_decode(value)) else {
+            continue;
+        };
+        if key.is_empty() {
+            continue;
+        }
+
+        match form.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(JsonValue::String(value));
+            }
+            Entry::Occupied(mut entry) => match entry.get_mut() {
+                JsonValue::Array(values) => values.push(JsonValue::String(value)),
+                first => {
+                    let first = first.take();
+                    *entry.get_mut() = JsonValue::Array(vec![first, JsonValue::String(value)]);
+                }
+            },
+        }
+    }
+    form
+}
+
+// Decodes the percent escapes and the `+` used for spaces. Returns None if an escape is
+// invalid or if the result is not valid UTF-8.
+fn percent_decode(input: &[u8]) -> Option<String> {
+    decode(input, true)
+}
+
+// Same as percent_decode() but `+` is kept as is, e.g. for a cookie value
+pub(crate) fn url_decode(input: &[u8]) -> Option<String> {
+    decode(input, false)
+}
+
+fn decode(input: &[u8], plus_as_space: bool) -> Option<String> {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut bytes = input.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'+' if plus_as_space => decoded.push(b' '),
+            b'%' => {
+                let hi = hex_value(*bytes.next()?)?;
+                let lo = hex_value(*bytes.next()?)?;
+                decoded.push(hi << 4 | lo);
+            }
+            _ => decoded.push(b),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(body: &str) -> JsonValue {
+        json!(parse_form_urlencoded(body.as_bytes()))
+    }
+
+    #[test]
+    fn test_parse_form_urlencoded() {
+        assert_eq!(
+            parse("client_id=abc&scope=read+write&redirect=https%3A%2F%2Fexample.com%2F"),
+            json!({
+                "client_id": "abc",
+                "scope": "read write",
+                "redirect": "https://example.com/"
+            })
+        );
+        assert_eq!(parse(""), json!({}));
+        // multi-byte UTF-8, encoded or not
+        assert_eq!(
+            parse("name=J%C3%B6rg&city=Zürich"),
+            json!({ "name": "Jörg", "city": "Zürich" })
+        );
+    }
+
+    #[test]
+    fn test_parse_form_urlencoded_repeated_keys() {
+        assert_eq!(
+            parse("scope=read&id=1&scope=write&scope=admin"),
+            json!({ "scope": ["read", "write", "admin"], "id": "1" })
+        );
+    }
+
+    #[test]
+    fn test_parse_form_urlencoded_malformed_pairs() {
+        // a key without value is an empty value, the empty pairs are ignored
+        assert_eq!(
+            parse("flag&&empty=&a=1&"),
+            json!({ "flag": "", "empty": "", "a": "1" })
+        );
+        // invalid escapes, invalid UTF-8 and empty keys are skipped
+        assert_eq!(
+            parse("bad=%zz&truncated=%4&invalid=%ff%fe&=nokey&sign=%+1&good=ok"),
+            json!({ "good": "ok" })
+        );
+        // only the first = separates the key from the value
+        assert_eq!(parse("expr=a=b"), json!({ "expr": "a=b" }));
+    }
+}
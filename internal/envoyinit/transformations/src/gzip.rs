@@ -0,0 +1,191 @@
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+// The Content-Encodings a body can be decompressed from before it is transformed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    // Returns the encoding of a Content-Encoding header value, None when it is not
+    // supported, e.g. br or a list of encodings
+    pub fn from_header(value: &[u8]) -> Option<Self> {
+        let value = value.trim_ascii();
+        if value.eq_ignore_ascii_case(b"gzip") || value.eq_ignore_ascii_case(b"x-gzip") {
+            Some(Encoding::Gzip)
+        } else if value.eq_ignore_ascii_case(b"deflate") {
+            Some(Encoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    // The Content-Encoding header value of a body compressed with compress()
+    pub fn header_value(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+// Decompresses a body for the transformation. The output is bounded by max_len so a small
+// compressed body cannot expand into an unbounded buffer. A gzip stream can be made of
+// several members, they are decompressed back to back. deflate is the zlib format, the
+// raw deflate some servers send instead is accepted too.
+pub fn decompress(encoding: Encoding, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        Encoding::Gzip => Box::new(MultiGzDecoder::new(data)),
+        Encoding::Deflate if is_zlib_header(data) => Box::new(ZlibDecoder::new(data)),
+        Encoding::Deflate => Box::new(DeflateDecoder::new(data)),
+    };
+    let name = encoding.header_value();
+    let mut out = Vec::new();
+    decoder
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut out)
+        .with_context(|| format!("{name}: invalid body"))?;
+    if out.len() > max_len {
+        bail!("{name}: the decompressed body is over {max_len} bytes");
+    }
+    Ok(out)
+}
+
+// Compresses a transformed body back with the encoding it was decompressed from
+pub fn compress(encoding: Encoding, data: &[u8]) -> Result<Vec<u8>> {
+    let compressed = match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+    };
+    Ok(compressed)
+}
+
+// A zlib stream starts with the deflate method and a header checksum, RFC 1950
+fn is_zlib_header(data: &[u8]) -> bool {
+    data.len() >= 2
+        && data[0] & 0x0f == 8
+        && u16::from_be_bytes([data[0], data[1]]).is_multiple_of(31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // gzip.compress(b"hello hello hello world", mtime=0), a fixed huffman block
+    const HELLO_GZ: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9, 0xc9,
+        0x57, 0xc8, 0x40, 0x22, 0xcb, 0xf3, 0x8b, 0x72, 0x52, 0x00, 0x26, 0xe6, 0x5a, 0x81, 0x17,
+        0x00, 0x00, 0x00,
+    ];
+    // gzip.compress(b"stored", mtime=0, compresslevel=0), a stored block
+    const STORED_GZ: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x03, 0x01, 0x06, 0x00, 0xf9, 0xff,
+        0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x0b, 0xf9, 0x43, 0x56, 0x06, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_decompress() {
+        assert_eq!(
+            decompress(Encoding::Gzip, HELLO_GZ, 1024).unwrap(),
+            b"hello hello hello world"
+        );
+        assert_eq!(
+            decompress(Encoding::Gzip, STORED_GZ, 1024).unwrap(),
+            b"stored"
+        );
+
+        // a dynamic huffman block
+        let items: Vec<String> = (0..40)
+            .map(|i| {
+                format!(
+                    r#"{{"id": {i}, "name": "item-{}", "tags": ["a", "b"]}}"#,
+                    i % 7
+                )
+            })
+            .collect();
+        let expected = format!(r#"{{"items": [{}]}}"#, items.join(", "));
+        let items_gz = include_bytes!("../testdata/items.json.gz");
+        assert_eq!(
+            String::from_utf8(decompress(Encoding::Gzip, items_gz, 4096).unwrap()).unwrap(),
+            expected
+        );
+
+        // concatenated members
+        let concatenated = [HELLO_GZ, STORED_GZ].concat();
+        assert_eq!(
+            decompress(Encoding::Gzip, &concatenated, 1024).unwrap(),
+            b"hello hello hello worldstored"
+        );
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        assert!(decompress(Encoding::Gzip, HELLO_GZ, 23).is_ok());
+        assert!(decompress(Encoding::Gzip, HELLO_GZ, 22).is_err());
+        assert!(decompress(
+            Encoding::Gzip,
+            include_bytes!("../testdata/items.json.gz"),
+            1024
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_decompress_invalid() {
+        assert!(decompress(Encoding::Gzip, b"not gzip at all", 1024).is_err());
+        // truncated
+        assert!(decompress(Encoding::Gzip, &HELLO_GZ[..20], 1024).is_err());
+        // corrupted checksum
+        let mut corrupted = HELLO_GZ.to_vec();
+        corrupted[25] ^= 0xff;
+        assert!(decompress(Encoding::Gzip, &corrupted, 1024).is_err());
+    }
+
+    #[test]
+    fn test_deflate() {
+        let zlib = compress(Encoding::Deflate, b"hello hello hello world").unwrap();
+        assert!(is_zlib_header(&zlib));
+        assert_eq!(
+            decompress(Encoding::Deflate, &zlib, 1024).unwrap(),
+            b"hello hello hello world"
+        );
+        // the raw deflate stream of HELLO_GZ, without the gzip header and trailer
+        assert_eq!(
+            decompress(Encoding::Deflate, &HELLO_GZ[10..HELLO_GZ.len() - 8], 1024).unwrap(),
+            b"hello hello hello world"
+        );
+        assert!(decompress(Encoding::Deflate, &zlib, 22).is_err());
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let body = br#"{"name": "foo", "items": [1, 2, 3]}"#;
+        for encoding in [Encoding::Gzip, Encoding::Deflate] {
+            let compressed = compress(encoding, body).unwrap();
+            assert_eq!(decompress(encoding, &compressed, 1024).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn test_encoding_from_header() {
+        assert_eq!(Encoding::from_header(b"gzip"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::from_header(b" X-GZIP "), Some(Encoding::Gzip));
+        assert_eq!(Encoding::from_header(b"deflate"), Some(Encoding::Deflate));
+        assert_eq!(Encoding::from_header(b"br"), None);
+        assert_eq!(Encoding::from_header(b"gzip, br"), None);
+    }
+}
@@ -1,38 +1,176 @@
+use crate::form;
+use crate::glob_match;
+use crate::secrets::Secrets;
+use crate::stage_name;
+use crate::AutoEscapeMode;
 use crate::BodyParseBehavior;
+use crate::BodyTransform;
+use crate::CopyPrefix;
+use crate::DynamicMetadata;
+use crate::ExtractionMode;
+use crate::Extractor;
+use crate::FunctionCategory;
+use crate::HeaderRemoval;
 use crate::LocalTransform;
 use crate::LocalTransformationConfig;
+use crate::MetadataFromBody;
 use crate::NameValuePair;
+use crate::OversizedValuePolicy;
 use crate::TransformationError;
 use crate::TransformationOps;
+use crate::TransformationStat;
 use anyhow::{Context, Error, Result};
 use base64::{
-    engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE},
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD},
     Engine,
 };
-use minijinja::{Environment, State};
+use heck::{ToKebabCase, ToLowerCamelCase, ToSnakeCase};
+use minijinja::value::{Enumerator, Object, ObjectRepr, Rest};
+use minijinja::{AutoEscape, Environment, State, UndefinedBehavior};
 use once_cell::sync::Lazy;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::Deserialize;
 use serde_json::Value as JsonValue;
-use std::collections::BTreeMap;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
 
 // These keys are used in a shared scope in the State where we will also put the parsed json body in.
 // So, they needs to be as uniq as possible to minimize collision.
 const STATE_LOOKUP_KEY_BODY: &str = "body.dev.kgateway";
+const STATE_LOOKUP_KEY_BODY_BASE64: &str = "body_base64.dev.kgateway";
 const STATE_LOOKUP_KEY_CONTEXT: &str = "context.dev.kgateway";
+const STATE_LOOKUP_KEY_EXTRACTIONS: &str = "extractions.dev.kgateway";
 const STATE_LOOKUP_KEY_HEADERS: &str = "headers.dev.kgateway";
 const STATE_LOOKUP_KEY_REQ_HEADERS: &str = "request_headers.dev.kgateway";
+const STATE_LOOKUP_KEY_RESP_HEADERS: &str = "response_headers.dev.kgateway";
+const STATE_LOOKUP_KEY_TRAILERS: &str = "trailers.dev.kgateway";
+const STATE_LOOKUP_KEY_RANDOM_SEED: &str = "random_seed.dev.kgateway";
+const STATE_LOOKUP_KEY_FILTER_STATE: &str = "filter_state.dev.kgateway";
+const STATE_LOOKUP_KEY_HEADER_COUNTS: &str = "header_counts.dev.kgateway";
+const STATE_LOOKUP_KEY_HEADER_VALUES: &str = "header_values.dev.kgateway";
+const STATE_LOOKUP_KEY_HEADER_BYTES: &str = "header_bytes.dev.kgateway";
+const STATE_LOOKUP_KEY_FORWARDED_FOR: &str = "forwarded_for.dev.kgateway";
+const STATE_LOOKUP_KEY_SOURCE_ADDRESS: &str = "source_address.dev.kgateway";
+const STATE_LOOKUP_KEY_TLS_SNI: &str = "tls_sni.dev.kgateway";
+const STATE_LOOKUP_KEY_CLIENT_CERT_SUBJECT: &str = "client_cert_subject.dev.kgateway";
+
+// When the body is parsed as json, the parsed body is also available under this name so
+// templates can do `{{ body.user.id }}`. It shadows the body() custom function, so the
+// object is callable and returns the same raw body string that body() would.
+const CONTEXT_KEY_PARSED_BODY: &str = "body";
+
+// The headers as a list of (name, value) pairs sorted by name, so templates can iterate
+// them with `{% for k, v in all_headers %}`. Like header(), it is the request headers
+// when rendering the request and the response headers when rendering the response.
+const CONTEXT_KEY_ALL_HEADERS: &str = "all_headers";
+
+// The name of the matched route, empty if the route has no per route config. It can be
+// used either as `{{ route_name }}` or `{{ route_name() }}`, the latter being a plain
+// string that can be compared, e.g. `{% if route_name() == "foo" %}`.
+const CONTEXT_KEY_ROUTE_NAME: &str = "route_name";
+
+// When the body is parsed as form-urlencoded, the form fields are available under this
+// name, e.g. `{{ form.client_id }}`. A repeated field is a list of its values. It is
+// undefined for the other parse modes.
+const CONTEXT_KEY_FORM: &str = "form";
+
+// The request body, available to the response templates as `request_body`. It is the
+// parsed json when the request body is json, the raw string otherwise.
+const CONTEXT_KEY_REQUEST_BODY: &str = "request_body";
+
+// When the body is streamed, the body template is rendered for each chunk with the chunk
+// as `chunk`, its position starting at 0 as `chunk_index`, and `end_of_stream` set for
+// the last one. With lines, each line of the body is a chunk.
+const CONTEXT_KEY_CHUNK: &str = "chunk";
+const CONTEXT_KEY_CHUNK_INDEX: &str = "chunk_index";
+const CONTEXT_KEY_END_OF_STREAM: &str = "end_of_stream";
+
+// Variables that can be in the context, so they are not undeclared variables
+const CONTEXT_KEYS: &[&str] = &[
+    CONTEXT_KEY_ALL_HEADERS,
+    CONTEXT_KEY_ROUTE_NAME,
+    CONTEXT_KEY_FORM,
+    CONTEXT_KEY_REQUEST_BODY,
+    CONTEXT_KEY_CHUNK,
+    CONTEXT_KEY_CHUNK_INDEX,
+    CONTEXT_KEY_END_OF_STREAM,
+];
 
 const REQUEST_BODY_TEMPLATE_LOOKUP_KEY: &str = "request_body_0";
 const RESPONSE_BODY_TEMPLATE_LOOKUP_KEY: &str = "response_body_0";
+const REQUEST_CONDITION_TEMPLATE_LOOKUP_KEY: &str = "request_condition_0";
+const RESPONSE_CONDITION_TEMPLATE_LOOKUP_KEY: &str = "response_condition_0";
 
-static ENV: Lazy<Environment<'static>> = Lazy::new(new_jinja_env);
+static ENV: Lazy<Environment<'static>> = Lazy::new(|| new_jinja_env(AutoEscapeMode::None));
 
 static GLOBALS_LOOKUP: Lazy<HashSet<&'static str>> =
     Lazy::new(|| ENV.globals().map(|(k, _)| k).collect());
 
+// The custom functions that EnvOptions can leave out of an env
+const OPTIONAL_FUNCTIONS: &[&str] = &["env", "replace_with_random", "crc32", "pseudonymize"];
+
+// Returns true if the name is one of our custom functions or a config var. Both are
+// registered as globals in the env so they are reported as undeclared variables by minijinja.
+// GLOBALS_LOOKUP is checked first as it covers the custom functions without having to
+// iterate through the env globals, except for the ones that might have been left out.
+fn is_global(env: &Environment<'static>, name: &str) -> bool {
+    (GLOBALS_LOOKUP.contains(name) && !OPTIONAL_FUNCTIONS.contains(&name))
+        || env.globals().any(|(k, _)| k == name)
+}
+
+// Returns true if the undeclared variable is rooted at one of the CONTEXT_KEYS, e.g.
+// `all_headers` or `all_headers.0` as reported by undeclared_variables(true)
+fn is_context_key(name: &str) -> bool {
+    let root = name.split('.').next().unwrap_or(name);
+    CONTEXT_KEYS.contains(&root)
+}
+
+fn sorted_headers(headers_map: &HashMap<String, String>) -> minijinja::Value {
+    let mut pairs: Vec<_> = headers_map.iter().collect();
+    pairs.sort();
+    minijinja::Value::from_serialize(&pairs)
+}
+
+// The context keys of the header accessors, updated after each set with sequentialSet
+const REQUEST_HEADER_KEYS: &[&str] = &[STATE_LOOKUP_KEY_HEADERS, STATE_LOOKUP_KEY_REQ_HEADERS];
+const RESPONSE_HEADER_KEYS: &[&str] = &[STATE_LOOKUP_KEY_HEADERS, STATE_LOOKUP_KEY_RESP_HEADERS];
+
+// With sequentialSet, records the new value of a header, None when it was removed, and
+// rebuilds the context so the next templates see it
+fn update_sequential_headers<M>(
+    sequential: &mut Option<(M, HashMap<String, String>)>,
+    header_keys: &[&str],
+    key: &str,
+    value: Option<&str>,
+    ctx: &mut minijinja::Value,
+) where
+    M: Extend<(String, minijinja::Value)> + Clone,
+    minijinja::Value: From<M>,
+{
+    let Some((m, headers)) = sequential else {
+        return;
+    };
+    let key = key.to_lowercase();
+    match value {
+        Some(value) => headers.insert(key, value.to_string()),
+        None => headers.remove(&key),
+    };
+    m.extend(
+        header_keys
+            .iter()
+            .map(|k| (k.to_string(), minijinja::Value::from_serialize(&*headers))),
+    );
+    m.extend([(CONTEXT_KEY_ALL_HEADERS.to_string(), sorted_headers(headers))]);
+    *ctx = minijinja::Value::from(m.clone());
+}
+
 // substring can be called with either two or three arguments --
 // the first argument is the string to be modified, the second is the start position
 // of the substring, and the optional third argument is the length of the substring.
@@ -75,11 +213,55 @@ fn header(state: &State, key: &str) -> String {
     lookup_header(headers, key)
 }
 
+// The trailer of the direction being transformed, only set when rendering the trailers
+fn trailer(state: &State, key: &str) -> String {
+    let trailers = state.lookup(STATE_LOOKUP_KEY_TRAILERS);
+    lookup_header(trailers, key)
+}
+
+// Returns the value of the named cookie from the request Cookie header, url-decoded, or
+// the default if there is no such cookie. The pairs without a `=` are skipped, and a value
+// that can't be decoded is returned as is.
+fn cookie(state: &State, name: &str, default: Option<&str>) -> String {
+    let cookies = lookup_header(state.lookup(STATE_LOOKUP_KEY_REQ_HEADERS), "cookie");
+    cookies
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| key.trim() == name)
+        .map(|(_, value)| {
+            let value = value.trim();
+            form::url_decode(value.as_bytes()).unwrap_or_else(|| value.to_string())
+        })
+        .or_else(|| default.map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn extraction(state: &State, name: &str) -> String {
+    state
+        .lookup(STATE_LOOKUP_KEY_EXTRACTIONS)
+        .and_then(|extractions| extractions.get_attr(name).ok())
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
 fn request_header(state: &State, key: &str) -> String {
     let headers = state.lookup(STATE_LOOKUP_KEY_REQ_HEADERS);
     lookup_header(headers, key)
 }
 
+// The response header, empty in the request templates as the response has not been
+// received yet. header() returns the same in the response templates.
+fn response_header(state: &State, key: &str) -> String {
+    let headers = state.lookup(STATE_LOOKUP_KEY_RESP_HEADERS);
+    lookup_header(headers, key)
+}
+
+// Returns the response :status, e.g. to map an upstream status to another with the status
+// template. Empty on the request.
+fn response_code(state: &State) -> String {
+    response_header(state, ":status")
+}
+
 fn trim_outer_quotes(s: &str) -> &str {
     if s.starts_with('"') && s.ends_with('"') && s.len() >= 2 {
         &s[1..s.len() - 1]
@@ -104,6 +286,52 @@ fn raw_string(value: &str) -> String {
     }
 }
 
+// A fast checksum for the cache keys or the ETags, e.g. `{{ crc32(header(":path")) }}`. It is
+// not a cryptographic hash, so it must not be used where the value has to be unguessable.
+// Returned as 8 lowercase hex digits.
+fn crc32(input: &str) -> String {
+    let mut crc = flate2::Crc::new();
+    crc.update(input.as_bytes());
+    format!("{:08x}", crc.sum())
+}
+
+// Pseudonymizes a value, e.g. a PII header, so it can still be correlated without being
+// revealed: `{{ pseudonymize(header("x-email"), env("PII_SALT")) }}`. Returns the first
+// length hex digits of sha256(salt + input), 16 by default and at most 64. The same input
+// and salt always give the same output, changing the salt changes all of them.
+fn pseudonymize(input: &str, salt: &str, length: Option<usize>) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(input)
+        .finalize();
+    let mut hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+    hex.truncate(length.unwrap_or(16));
+    hex
+}
+
+// Looks a key up in one of the tables of the config, e.g. to map a country code to a region.
+// Returns the default when the key is not in the table, empty without a default. A table
+// that doesn't exist is an error, the name is a typo in the template or the config.
+fn lookup(
+    tables: &HashMap<String, HashMap<String, String>>,
+    table: &str,
+    key: &str,
+    default: Option<&str>,
+) -> Result<String, minijinja::Error> {
+    let Some(table) = tables.get(table) else {
+        return Err(minijinja::Error::new(
+            minijinja::ErrorKind::InvalidOperation,
+            format!("lookup: no table named {table}"),
+        ));
+    };
+    Ok(table
+        .get(key)
+        .map(String::as_str)
+        .or(default)
+        .unwrap_or_default()
+        .to_string())
+}
+
 fn base64_encode(input: &[u8]) -> String {
     STANDARD.encode(input)
 }
@@ -128,15 +356,161 @@ fn base64url_decode(input: &str) -> String {
         .unwrap_or_default()
 }
 
-fn get_env(env_var: &str) -> String {
-    env::var(env_var).unwrap_or_default()
+// jwt_claim returns a top level claim of a JWT, e.g. `{{ jwt_claim(token, "sub") }}`. The
+// signature is NOT verified, that's the job of the auth filter. A claim that is not a string
+// is returned as json and a missing claim as an empty string. A malformed token is a render
+// error, so it is reported and the header is left out like for any other render error.
+fn jwt_claim(token: &str, claim: &str) -> std::result::Result<String, minijinja::Error> {
+    let malformed = |detail: &str| {
+        minijinja::Error::new(
+            minijinja::ErrorKind::InvalidOperation,
+            format!("malformed jwt: {detail}"),
+        )
+    };
+    let mut segments = token.trim().split('.');
+    let (Some(_header), Some(payload), Some(_signature), None) = (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) else {
+        return Err(malformed("expected 3 segments"));
+    };
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(|_| malformed("invalid base64url payload"))?;
+    let claims: JsonValue =
+        serde_json::from_slice(&payload).map_err(|_| malformed("invalid json payload"))?;
+    Ok(match claims.get(claim) {
+        None => String::new(),
+        Some(JsonValue::String(value)) => value.clone(),
+        Some(value) => value.to_string(),
+    })
+}
+
+// basic_auth_user and basic_auth_pass return the user and the password of a Basic
+// Authorization header value, e.g. `{{ basic_auth_user(request_header("authorization")) }}`.
+// The credentials are split on the first colon, so the password may contain colons. The
+// credentials are NOT checked. A value that is not valid Basic credentials, e.g. another
+// scheme or bad base64, returns an empty string for both.
+fn basic_auth_user(header_value: &str) -> String {
+    basic_auth_credentials(header_value)
+        .map(|(user, _)| user)
+        .unwrap_or_default()
+}
+
+fn basic_auth_pass(header_value: &str) -> String {
+    basic_auth_credentials(header_value)
+        .map(|(_, pass)| pass)
+        .unwrap_or_default()
+}
+
+fn basic_auth_credentials(header_value: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = header_value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, pass) = decoded.split_once(':')?;
+    Some((user.to_string(), pass.to_string()))
+}
+
+// to_int and to_float parse a number out of a string, e.g. a header value, so it can be used
+// in arithmetic: `{{ to_int(header("x-count"), 0) + 1 }}`. Leading and trailing whitespace
+// is ignored and the default is returned if the input is not a valid number.
+fn to_int(input: &str, default: i64) -> i64 {
+    input.trim().parse().unwrap_or(default)
+}
+
+// Like to_int, the non finite values (inf, NaN) are not considered valid numbers
+fn to_float(input: &str, default: f64) -> f64 {
+    input
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|f| f.is_finite())
+        .unwrap_or(default)
+}
+
+// Returns the first argument that is not empty, e.g. to fall back from one header to another.
+// Undefined and none values count as empty.
+fn coalesce(args: Rest<minijinja::Value>) -> String {
+    args.iter()
+        .filter(|v| !v.is_undefined() && !v.is_none())
+        .map(|v| v.to_string())
+        .find(|v| !v.is_empty())
+        .unwrap_or_default()
+}
+
+// to_snake, to_kebab and to_camel convert between naming conventions, e.g.
+// `{{ to_kebab(header("x-field-name")) }}`. The words are split on the non alphanumeric
+// characters and on the case changes, so `XFieldName`, `x_field-name` and `x field name`
+// are the same three words. An input already converted is returned as is, and the
+// acronyms are kept as one word: `HTTPRequest` is `http_request`. to_camel is lower
+// camel case, `xFieldName`.
+fn to_snake(input: &str) -> String {
+    input.to_snake_case()
+}
+
+fn to_kebab(input: &str) -> String {
+    input.to_kebab_case()
+}
+
+fn to_camel(input: &str) -> String {
+    input.to_lower_camel_case()
+}
+
+// pad_left and pad_right pad the input with the fill char up to width chars, e.g.
+// `{{ pad_left(header("x-id"), 8, "0") }}` for a zero padded id. Only the first char of
+// fill is used (a space if it's empty). An input already longer than width is returned
+// as is rather than truncated, so no data is lost.
+fn pad_left(input: &str, width: usize, fill: &str) -> String {
+    let padding = padding(input, width, fill);
+    padding + input
+}
+
+fn pad_right(input: &str, width: usize, fill: &str) -> String {
+    let padding = padding(input, width, fill);
+    input.to_string() + &padding
+}
+
+fn padding(input: &str, width: usize, fill: &str) -> String {
+    let fill = fill.chars().next().unwrap_or(' ');
+    let len = input.chars().count();
+    std::iter::repeat_n(fill, width.saturating_sub(len)).collect()
+}
+
+// Returns the value of the environment variable, or the default when it is unset, e.g.
+// `env("REGION", "us-east-1")`
+fn get_env(env_var: &str, default: Option<&str>) -> String {
+    env::var(env_var)
+        .ok()
+        .or_else(|| default.map(str::to_string))
+        .unwrap_or_default()
+}
+
+// Returns the seed of the random functions for a randomSeedHeader value. FNV-1a, so the
+// seed doesn't change across processes.
+pub fn random_seed(value: &[u8]) -> u64 {
+    value.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
 }
 
-fn replace_with_random(input: &str, to_replace: &str) -> String {
-    // TODO: in the C++ version, the pattern is generated once per "to_replace" string
-    //       and get re-used for all calls within the request context but I cannot find
-    //       a way to do this here yet
-    let mut rng = rand::rng();
+// When the request has a random seed, the pattern only depends on the seed and on
+// "to_replace", so like in the C++ version it is the same for all the calls with the same
+// "to_replace" string within the request.
+fn replace_with_random(state: &State, input: &str, to_replace: &str) -> String {
+    let mut rng: Box<dyn rand::RngCore> = match state
+        .lookup(STATE_LOOKUP_KEY_RANDOM_SEED)
+        .and_then(|seed| u64::try_from(seed).ok())
+    {
+        Some(seed) => Box::new(StdRng::seed_from_u64(
+            seed ^ random_seed(to_replace.as_bytes()),
+        )),
+        None => Box::new(rand::rng()),
+    };
     let high: u64 = rng.random();
     let low: u64 = rng.random();
     let mut random = [0u8; 16];
@@ -158,12 +532,524 @@ fn body(state: &State) -> String {
         .to_string()
 }
 
+// The downstream remote address, without the port unless include_port is set.
+// A bracketed IPv6 address with a port, e.g. `[::1]:8080`, is returned as `::1`.
+fn source_ip(state: &State, include_port: Option<bool>) -> String {
+    let address = state
+        .lookup(STATE_LOOKUP_KEY_SOURCE_ADDRESS)
+        .unwrap_or_default()
+        .to_string();
+    if include_port.unwrap_or(false) {
+        return address;
+    }
+    match address.parse::<SocketAddr>() {
+        Ok(socket_addr) => socket_addr.ip().to_string(),
+        // no port to strip, e.g. a pipe path
+        Err(_) => address,
+    }
+}
+
+// The client address from the X-Forwarded-For request header, after dropping trusted_hops
+// entries from the right, that is the addresses appended by the trusted proxies in front of
+// envoy. All the X-Forwarded-For headers of the request are read, in order. The address
+// is returned without its port or brackets, e.g. `2001:db8::1` for `[2001:db8::1]:443`.
+// Empty when there are not enough entries or when the entry is not an IP address, a
+// malformed entry is never skipped since the ones before it can't be trusted.
+fn client_ip_from_xff(state: &State, trusted_hops: Option<usize>) -> String {
+    let Some(forwarded_for) = state.lookup(STATE_LOOKUP_KEY_FORWARDED_FOR) else {
+        return String::new();
+    };
+    let entries: Vec<String> = forwarded_for
+        .try_iter()
+        .into_iter()
+        .flatten()
+        .flat_map(|value| {
+            value
+                .to_string()
+                .split(',')
+                .map(|entry| entry.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    entries
+        .len()
+        .checked_sub(trusted_hops.unwrap_or(0) + 1)
+        .and_then(|index| parse_forwarded_ip(&entries[index]))
+        .map(|ip| ip.to_string())
+        .unwrap_or_default()
+}
+
+// Parses an X-Forwarded-For entry, e.g. `192.0.2.1`, `192.0.2.1:8080`, `2001:db8::1`,
+// `[2001:db8::1]` or `[2001:db8::1]:8080`
+fn parse_forwarded_ip(entry: &str) -> Option<IpAddr> {
+    if let Ok(ip) = entry.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(rest) = entry.strip_prefix('[') {
+        let (ip, port) = rest.split_once(']')?;
+        if !port.is_empty() {
+            port.strip_prefix(':')?.parse::<u16>().ok()?;
+        }
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    entry.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+// The server name the client asked for in the TLS handshake, empty for a plaintext
+// connection or when the client sent no SNI
+fn tls_sni(state: &State) -> String {
+    state
+        .lookup(STATE_LOOKUP_KEY_TLS_SNI)
+        .unwrap_or_default()
+        .to_string()
+}
+
+// The subject of the client certificate of an mTLS connection, e.g.
+// `CN=client,O=example`, empty when the client presented no certificate
+fn client_cert_subject(state: &State) -> String {
+    state
+        .lookup(STATE_LOOKUP_KEY_CLIENT_CERT_SUBJECT)
+        .unwrap_or_default()
+        .to_string()
+}
+
+// The variables and functions reading the body, besides the json body fields
+const BODY_VARIABLES: &[&str] = &[
+    CONTEXT_KEY_PARSED_BODY,
+    CONTEXT_KEY_FORM,
+    "body_base64",
+    "body_json",
+    "context",
+];
+
+// Returns true if the transform has to wait for the full body: to rewrite it, or because
+// the body is parsed and a header template reads it. A template reading it is detected
+// conservatively, any variable that is not a function, a var or a context key is assumed
+// to be a json body field.
+pub fn transform_needs_body(env: &Environment<'static>, transform: &LocalTransform) -> bool {
+    if !transform.needs_body() {
+        return false;
+    }
+    let Some(body) = transform.body_transform() else {
+        return false;
+    };
+    if !body.value.is_empty() || body.merge.is_some() || body.remove_body {
+        return true;
+    }
+    if !transform.metadata_from_body.is_empty() {
+        return true;
+    }
+    transform
+        .add
+        .iter()
+        .chain(&transform.set)
+        .filter(|pair| !pair.literal)
+        .map(|pair| &pair.value)
+        .chain(&transform.host_rewrite)
+        .chain(&transform.status)
+        .chain(
+            transform
+                .dynamic_metadata
+                .iter()
+                .map(|metadata| &metadata.value),
+        )
+        .map(String::as_str)
+        .chain(transform.remove.iter().filter_map(HeaderRemoval::template))
+        .any(|template| template_reads_body(env, template))
+}
+
+fn template_reads_body(env: &Environment<'static>, template: &str) -> bool {
+    if template.is_empty() {
+        return false;
+    }
+    let Ok(tmpl) = env.get_template(template) else {
+        return true;
+    };
+    tmpl.undeclared_variables(false)
+        .iter()
+        .any(|v| BODY_VARIABLES.contains(&v.as_str()) || (!is_global(env, v) && !is_context_key(v)))
+}
+
+// Returns true if the transform, a response one, uses request_body so the request body has
+// to be kept around until the response
+pub fn uses_request_body(transform: &LocalTransform) -> bool {
+    let mut templates: Vec<&str> = transform
+        .add
+        .iter()
+        .chain(&transform.set)
+        .filter(|pair| !pair.literal)
+        .map(|pair| pair.value.as_str())
+        .chain(transform.dynamic_metadata.iter().map(|m| m.value.as_str()))
+        .collect();
+    if let Some(body) = &transform.body {
+        templates.push(&body.value);
+        if let Some(patch) = &body.merge {
+            merge_patch_templates(patch, &mut templates);
+        }
+    }
+    templates
+        .iter()
+        .any(|template| template.contains(CONTEXT_KEY_REQUEST_BODY))
+}
+
+// The variables and functions reading the request headers
+const HEADER_VARIABLES: &[&str] = &[
+    CONTEXT_KEY_ALL_HEADERS,
+    "header",
+    "request_header",
+    "cookie",
+];
+
+// Returns true if the request headers map has to be built, either because a template reads
+// the headers or because the request transform matches on them, e.g. for the extractors, the
+// body transform checking the Content-Encoding, the hostRewrite checking for a Host header,
+// or to tell if clearRouteCache has to clear the route cache. Otherwise building the map
+// is skipped.
+pub fn uses_request_headers(
+    env: &Environment<'static>,
+    config: &LocalTransformationConfig,
+) -> bool {
+    let request_reads_headers = config
+        .request
+        .iter()
+        .flat_map(LocalTransform::stages)
+        .any(|t| {
+            t.body_transform().is_some()
+                || !t.extractors.is_empty()
+                || t.remove.iter().any(|removal| removal.name().is_none())
+                || !t.copy_prefix.is_empty()
+                || t.host_rewrite.is_some()
+                || t.clear_route_cache
+        });
+    // the methods are matched on the request headers in both directions
+    let matches_methods = config
+        .request
+        .iter()
+        .chain(config.response.iter())
+        .flat_map(LocalTransform::stages)
+        .any(|t| !t.methods.is_empty());
+    request_reads_headers
+        || matches_methods
+        || env.templates().any(|(_, tmpl)| {
+            tmpl.undeclared_variables(false)
+                .iter()
+                .any(|v| HEADER_VARIABLES.contains(&v.as_str()))
+        })
+}
+
+// Applies the maximum size to a rendered header value. A dropped value is returned empty.
+fn limit_header_value<T: TransformationOps>(
+    ops: &T,
+    key: &str,
+    value: String,
+    max_bytes: Option<usize>,
+    policy: OversizedValuePolicy,
+) -> String {
+    let Some(max_bytes) = max_bytes.filter(|max| value.len() > *max) else {
+        return value;
+    };
+    ops.log_debug(&format!(
+        "header {key} value is {} bytes, over the {max_bytes} bytes limit",
+        value.len()
+    ));
+    let (max_bytes, suffix) = match policy {
+        OversizedValuePolicy::Drop => return String::new(),
+        OversizedValuePolicy::Truncate => (max_bytes, ""),
+        OversizedValuePolicy::Ellipsis => match max_bytes.checked_sub(ELLIPSIS.len()) {
+            Some(max_bytes) => (max_bytes, ELLIPSIS),
+            None => (max_bytes, ""),
+        },
+    };
+    let mut end = max_bytes;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{suffix}", &value[..end])
+}
+
+const ELLIPSIS: &str = "...";
+
+// Returns true if any template refers to one of the functions. The functions are globals,
+// so minijinja reports them as undeclared variables, however the call is spelled.
+fn templates_use(env: &Environment<'static>, functions: &[&str]) -> bool {
+    env.templates().any(|(_, tmpl)| {
+        tmpl.undeclared_variables(false)
+            .iter()
+            .any(|v| functions.contains(&v.as_str()))
+    })
+}
+
+// Returns true if any template calls source_ip(), so the address is only looked up
+// when it is used
+pub fn uses_source_ip(env: &Environment<'static>) -> bool {
+    templates_use(env, &["source_ip"])
+}
+
+// Returns true if any template calls tls_sni() or client_cert_subject(), so the TLS
+// information of the connection is only looked up when it is used
+pub fn uses_tls_info(env: &Environment<'static>) -> bool {
+    templates_use(env, &["tls_sni", "client_cert_subject"])
+}
+
+// Returns true if any template calls header_count(), so the request headers are only
+// counted when it is used
+pub fn uses_header_count(env: &Environment<'static>) -> bool {
+    templates_use(env, &["header_count"])
+}
+
+// Returns true if any template calls header_values() or client_ip_from_xff(), so the
+// values of the repeated headers are only kept when they are used
+pub fn uses_header_values(env: &Environment<'static>) -> bool {
+    templates_use(env, &["header_values", "client_ip_from_xff"])
+}
+
+// Returns true if any template calls header_bytes_base64(), so the raw header values are
+// only kept when it is used
+pub fn uses_header_bytes(env: &Environment<'static>) -> bool {
+    templates_use(env, &["header_bytes_base64"])
+}
+
+static FILTER_STATE_CALL: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r#"filter_state\(\s*(?:"([^"]*)"|'([^']*)')\s*\)"#).unwrap());
+
+// Returns the filter state keys read by the templates, so only those are looked up. The
+// keys have to be string literals, e.g. `filter_state("envoy.ratelimit.decision")`.
+pub fn filter_state_keys(env: &Environment<'static>) -> Vec<String> {
+    let mut keys: Vec<String> = env
+        .templates()
+        .flat_map(|(_, tmpl)| {
+            FILTER_STATE_CALL
+                .captures_iter(tmpl.source())
+                .filter_map(|caps| caps.get(1).or_else(|| caps.get(2)))
+                .map(|key| key.as_str().to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+// The string filter state set by the previous filters, e.g. ext_authz. A missing key or a
+// key that is not a string literal in the template renders as an empty string.
+fn filter_state(state: &State, key: &str) -> String {
+    state
+        .lookup(STATE_LOOKUP_KEY_FILTER_STATE)
+        .and_then(|filter_state| filter_state.get_attr(key).ok())
+        .filter(|value| !value.is_undefined())
+        .map(|value| value.to_string())
+        .unwrap_or_default()
+}
+
+// How many times the header appears in the original request, 0 when it is missing. Unlike
+// header(), it is the request headers in the response templates too. A header repeated
+// with different values, e.g. `content-length`, can be a request smuggling attempt.
+fn header_count(state: &State, key: &str) -> usize {
+    state
+        .lookup(STATE_LOOKUP_KEY_HEADER_COUNTS)
+        .and_then(|counts| counts.get_attr(&key.to_lowercase()).ok())
+        .and_then(|count| usize::try_from(count).ok())
+        .unwrap_or_default()
+}
+
+// All the values of the header in the order they were received, an empty list when it is
+// missing. header() only returns one of them, see duplicateHeaders. Like header(), they
+// are the response headers in the response templates. In the request templates, they are
+// the original request headers, before any stage changed them.
+fn header_values(state: &State, key: &str) -> minijinja::Value {
+    state
+        .lookup(STATE_LOOKUP_KEY_HEADER_VALUES)
+        .and_then(|values| values.get_attr(&key.to_lowercase()).ok())
+        .filter(|values| !values.is_undefined())
+        .unwrap_or_else(|| minijinja::Value::from(Vec::<String>::new()))
+}
+
+// The raw value of the header as a base64 string, for the values that are not valid UTF-8
+// and that header() decodes lossily. Empty when the header is missing.
+fn header_bytes_base64(state: &State, key: &str) -> String {
+    state
+        .lookup(STATE_LOOKUP_KEY_HEADER_BYTES)
+        .and_then(|bytes| bytes.get_attr(&key.to_lowercase()).ok())
+        .filter(|value| !value.is_undefined())
+        .map(|value| value.to_string())
+        .unwrap_or_default()
+}
+
+// The raw body as a base64 string, for the binary bodies that body() would mangle
+fn body_base64(state: &State) -> String {
+    state
+        .lookup(STATE_LOOKUP_KEY_BODY_BASE64)
+        .unwrap_or_default()
+        .to_string()
+}
+
+// The parsed json body exposed to the templates as `body`
+#[derive(Debug)]
+struct JsonBody {
+    json: JsonValue,
+    value: minijinja::Value,
+}
+
+impl JsonBody {
+    fn new_value(json: JsonValue) -> minijinja::Value {
+        let value = minijinja::Value::from_serialize(&json);
+        minijinja::Value::from_object(JsonBody { json, value })
+    }
+}
+
+impl Object for JsonBody {
+    fn repr(self: &Arc<Self>) -> ObjectRepr {
+        match self.json {
+            JsonValue::Array(_) => ObjectRepr::Seq,
+            _ => ObjectRepr::Map,
+        }
+    }
+
+    fn get_value(self: &Arc<Self>, key: &minijinja::Value) -> Option<minijinja::Value> {
+        self.value.get_item(key).ok().filter(|v| !v.is_undefined())
+    }
+
+    fn enumerate(self: &Arc<Self>) -> Enumerator {
+        match self.value.try_iter() {
+            Ok(iter) => Enumerator::Values(iter.collect()),
+            Err(_) => Enumerator::NonEnumerable,
+        }
+    }
+
+    // keeps `{{ body() }}` working when the body is parsed as json
+    fn call(
+        self: &Arc<Self>,
+        state: &State<'_, '_>,
+        _args: &[minijinja::Value],
+    ) -> std::result::Result<minijinja::Value, minijinja::Error> {
+        Ok(minijinja::Value::from(body(state)))
+    }
+
+    fn render(self: &Arc<Self>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.json)
+    }
+}
+
+// The route name exposed to the templates as `route_name`
+#[derive(Debug)]
+struct RouteName(String);
+
+impl Object for RouteName {
+    fn repr(self: &Arc<Self>) -> ObjectRepr {
+        ObjectRepr::Plain
+    }
+
+    fn is_true(self: &Arc<Self>) -> bool {
+        !self.0.is_empty()
+    }
+
+    // route_name() returns the name as a string
+    fn call(
+        self: &Arc<Self>,
+        _state: &State<'_, '_>,
+        _args: &[minijinja::Value],
+    ) -> std::result::Result<minijinja::Value, minijinja::Error> {
+        Ok(minijinja::Value::from(self.0.as_str()))
+    }
+
+    fn render(self: &Arc<Self>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// json_pointer looks up a value using a RFC 6901 json pointer, e.g.
+// `json_pointer(body, "/items/0/sku")`. Returns undefined if the pointer doesn't resolve.
+fn json_pointer(value: minijinja::Value, pointer: &str) -> minijinja::Value {
+    let resolved = match value.downcast_object_ref::<JsonBody>() {
+        Some(body) => body.json.pointer(pointer).cloned(),
+        None => JsonValue::deserialize(value)
+            .ok()
+            .and_then(|json| json.pointer(pointer).cloned()),
+    };
+    resolved
+        .map(|v| minijinja::Value::from_serialize(&v))
+        .unwrap_or_default()
+}
+
+// The json body, e.g. `{{ body_json().user.id }}`, undefined when the body is not parsed as
+// json. Unlike the top level fields it can't be shadowed by a var or a context key.
+fn body_json(state: &State) -> minijinja::Value {
+    state
+        .lookup(CONTEXT_KEY_PARSED_BODY)
+        .and_then(|value| {
+            value
+                .downcast_object_ref::<JsonBody>()
+                .map(|body| body.value.clone())
+        })
+        .unwrap_or_default()
+}
+
 fn context(state: &State) -> minijinja::Value {
     state.lookup(STATE_LOOKUP_KEY_CONTEXT).unwrap_or_default()
 }
 
-pub fn new_jinja_env() -> Environment<'static> {
+// The context() of a config with secrets, a body echoing a secret value is dumped redacted
+fn redacted_context(secrets: &Secrets, context: minijinja::Value) -> minijinja::Value {
+    match JsonValue::deserialize(context) {
+        Ok(json) => minijinja::Value::from_serialize(secrets.redact_json(json)),
+        Err(_) => minijinja::Value::UNDEFINED,
+    }
+}
+
+// The options of new_jinja_env_with(), the function categories are all enabled by default
+#[derive(Debug, Clone, Copy)]
+pub struct EnvOptions {
+    pub auto_escape: AutoEscapeMode,
+    // env()
+    pub env_access: bool,
+    // replace_with_random()
+    pub randomness: bool,
+    // crc32() and pseudonymize()
+    pub hashing: bool,
+}
+
+impl Default for EnvOptions {
+    fn default() -> Self {
+        EnvOptions {
+            auto_escape: AutoEscapeMode::default(),
+            env_access: true,
+            randomness: true,
+            hashing: true,
+        }
+    }
+}
+
+impl EnvOptions {
+    // Returns the options of a config, with its disabledFunctions left out
+    pub fn from_config(config: &LocalTransformationConfig) -> Self {
+        let enabled = |category| !config.disabled_functions.contains(&category);
+        EnvOptions {
+            auto_escape: config.auto_escape,
+            env_access: enabled(FunctionCategory::Env),
+            randomness: enabled(FunctionCategory::Random),
+            hashing: enabled(FunctionCategory::Hashing),
+        }
+    }
+}
+
+pub fn new_jinja_env(auto_escape: AutoEscapeMode) -> Environment<'static> {
+    new_jinja_env_with(EnvOptions {
+        auto_escape,
+        ..EnvOptions::default()
+    })
+}
+
+// Same as new_jinja_env() with some function categories left out. A template calling a
+// function that is left out fails to render like for any unknown function.
+pub fn new_jinja_env_with(options: EnvOptions) -> Environment<'static> {
     let mut env = Environment::new();
+    let auto_escape = match options.auto_escape {
+        AutoEscapeMode::None => AutoEscape::None,
+        AutoEscapeMode::Json => AutoEscape::Json,
+        AutoEscapeMode::Html => AutoEscape::Html,
+    };
+    // The templates are not named after files, so the escaping can't be picked by extension
+    env.set_auto_escape_callback(move |_name| auto_escape);
 
     // if parseAsJson is used for body parsing. minijinja would prefer the json instead of custom function
     // when rendering the template. For example, we have this `env()` function here, if the json body also has
@@ -171,7 +1057,9 @@ pub fn new_jinja_env() -> Environment<'static> {
     // `env` to the json value from the body and then will complain it's not callable.
     // If we are adding any new functions, we should make the function name more uniq to minimize the chance
     // of collision.
-    env.add_function("env", get_env);
+    if options.env_access {
+        env.add_function("env", get_env);
+    }
     env.add_function("substring", substring);
 
     // !! Standard string manipulation
@@ -180,16 +1068,75 @@ pub fn new_jinja_env() -> Environment<'static> {
     env.add_function("base64url_encode", base64url_encode);
     env.add_function("base64_decode", base64_decode);
     env.add_function("base64url_decode", base64url_decode);
-    env.add_function("replace_with_random", replace_with_random);
+    if options.randomness {
+        env.add_function("replace_with_random", replace_with_random);
+    }
     env.add_function("replace_with_string", replace_with_string);
     env.add_function("raw_string", raw_string);
+    env.add_function("json_pointer", json_pointer);
+    env.add_function("jwt_claim", jwt_claim);
+    env.add_function("basic_auth_user", basic_auth_user);
+    env.add_function("basic_auth_pass", basic_auth_pass);
+    env.add_function("to_int", to_int);
+    env.add_function("to_float", to_float);
+    env.add_function("pad_left", pad_left);
+    env.add_function("pad_right", pad_right);
+    env.add_function("to_snake", to_snake);
+    env.add_function("to_kebab", to_kebab);
+    env.add_function("to_camel", to_camel);
+    env.add_function("coalesce", coalesce);
+    if options.hashing {
+        env.add_function("crc32", crc32);
+        env.add_function("pseudonymize", pseudonymize);
+    }
     //        env.add_function("word_count", word_count);
 
+    // The string helpers are also filters, so `{{ header("x-id") | base64_encode }}` works
+    // the same as `{{ base64_encode(header("x-id")) }}`. The input is the first argument.
+    env.add_filter("substring", substring);
+    env.add_filter("base64_encode", base64_encode);
+    env.add_filter("base64url_encode", base64url_encode);
+    env.add_filter("base64_decode", base64_decode);
+    env.add_filter("base64url_decode", base64url_decode);
+    if options.randomness {
+        env.add_filter("replace_with_random", replace_with_random);
+    }
+    env.add_filter("replace_with_string", replace_with_string);
+    env.add_filter("raw_string", raw_string);
+    env.add_filter("jwt_claim", jwt_claim);
+    env.add_filter("basic_auth_user", basic_auth_user);
+    env.add_filter("basic_auth_pass", basic_auth_pass);
+    env.add_filter("to_int", to_int);
+    env.add_filter("to_float", to_float);
+    env.add_filter("pad_left", pad_left);
+    env.add_filter("pad_right", pad_right);
+    env.add_filter("to_snake", to_snake);
+    env.add_filter("to_kebab", to_kebab);
+    env.add_filter("to_camel", to_camel);
+    if options.hashing {
+        env.add_filter("crc32", crc32);
+        env.add_filter("pseudonymize", pseudonymize);
+    }
+
     // !! Envoy context accessors
     env.add_function("header", header);
     env.add_function("request_header", request_header);
-    // env.add_function("extraction", extraction);
+    env.add_function("trailer", trailer);
+    env.add_function("response_header", response_header);
+    env.add_function("response_code", response_code);
+    env.add_function("source_ip", source_ip);
+    env.add_function("tls_sni", tls_sni);
+    env.add_function("client_cert_subject", client_cert_subject);
+    env.add_function("extraction", extraction);
+    env.add_function("cookie", cookie);
     env.add_function("body", body);
+    env.add_function("body_base64", body_base64);
+    env.add_function("body_json", body_json);
+    env.add_function("filter_state", filter_state);
+    env.add_function("header_count", header_count);
+    env.add_function("header_values", header_values);
+    env.add_function("header_bytes_base64", header_bytes_base64);
+    env.add_function("client_ip_from_xff", client_ip_from_xff);
     // env.add_function("dynamic_metadata", dynamic_metadata);
 
     // !! Datasource Puller needed
@@ -235,9 +1182,8 @@ fn render(
             for v in &undeclared_variables {
                 // Unfortunately, custom function is also reported as undeclared variables
                 // by minijinja, so only return error if the undeclared variables are not
-                // custom functions. GLOBALS_LOCKUP is lazily constructed once and is
-                // static throughout the lifetime of the process.
-                if !GLOBALS_LOOKUP.contains(v.as_str()) {
+                // custom functions, config vars or the variables we always set.
+                if !is_global(env, v) && !is_context_key(v) {
                     return Err(TransformationError::UndeclaredJsonVariables(format!(
                         "{:?} from template {}",
                         undeclared_variables, template
@@ -251,56 +1197,606 @@ fn render(
         .with_context(|| format!("error rendering jinja template {}", template))
 }
 
-fn combine_errors(msg: &str, errors: Vec<Error>) -> Result<()> {
-    // Each error can have multiple level of errors, that's why there is
-    // the e.chain() iterating through each error and combine them
-    if !errors.is_empty() {
-        let combined = errors
-            .into_iter()
-            .map(|e| {
-                e.chain()
-                    .map(|cause| cause.to_string())
-                    .collect::<Vec<String>>()
-                    .join(":")
-            })
-            .collect::<Vec<_>>()
-            .join("; ");
-        return Err(anyhow::anyhow!("{}: {}", msg, combined));
+// Renders a header template, the header templates are looked up by their source. The
+// render duration is reported to ops. A literal value is returned as is, it has no
+// template in env.
+fn render_header<T: TransformationOps>(
+    env: &Environment<'static>,
+    ctx: &minijinja::Value,
+    template: &str,
+    literal: bool,
+    parsed_body_as_json: bool,
+    ops: &mut T,
+) -> Result<String> {
+    if literal {
+        return Ok(template.to_string());
     }
-
-    Ok(())
+    let started = Instant::now();
+    let rendered = render(env, ctx, template, template, parsed_body_as_json);
+    ops.record_render_duration(started.elapsed());
+    rendered
 }
 
-/// Transform Request
-///
-/// On any header rendering errors, we will remove the header and continue
-/// All the errors are collected and bubble up the chain so they can be logged
-/// On body parsing as json error, we return error immediately so we can send a
-/// 400 response back
-pub fn transform_request<T: TransformationOps>(
+// Renders the string values of a merge patch as templates. The keys are used as is.
+fn render_merge_patch(
     env: &Environment<'static>,
-    transform: &LocalTransform,
-    request_headers_map: &HashMap<String, String>,
-    mut ops: T,
-) -> Result<()> {
-    let mut errors = Vec::new();
+    ctx: &minijinja::Value,
+    patch: &JsonValue,
+    parsed_body_as_json: bool,
+) -> Result<JsonValue> {
+    Ok(match patch {
+        JsonValue::String(template) => {
+            JsonValue::String(render(env, ctx, template, template, parsed_body_as_json)?)
+        }
+        JsonValue::Array(items) => JsonValue::Array(
+            items
+                .iter()
+                .map(|item| render_merge_patch(env, ctx, item, parsed_body_as_json))
+                .collect::<Result<_>>()?,
+        ),
+        JsonValue::Object(map) => JsonValue::Object(
+            map.iter()
+                .map(|(k, v)| {
+                    Ok((
+                        k.clone(),
+                        render_merge_patch(env, ctx, v, parsed_body_as_json)?,
+                    ))
+                })
+                .collect::<Result<_>>()?,
+        ),
+        other => other.clone(),
+    })
+}
 
-    //    let mut m = BTreeMap::new();
-    let mut m = HashMap::new();
-    // for request rendering, both the header() and request_header() use the request_headers
-    // so, setting both to the request_headers_map in the context
-    m.insert(
-        STATE_LOOKUP_KEY_HEADERS.to_string(),
-        minijinja::Value::from_serialize(request_headers_map),
-    );
-    m.insert(
-        STATE_LOOKUP_KEY_REQ_HEADERS.to_string(),
-        minijinja::Value::from_serialize(request_headers_map),
-    );
+// Collects the templates of a merge patch so they can be compiled with the config
+fn merge_patch_templates<'a>(patch: &'a JsonValue, templates: &mut Vec<&'a str>) {
+    match patch {
+        JsonValue::String(template) if !template.is_empty() => templates.push(template),
+        JsonValue::Array(items) => items
+            .iter()
+            .for_each(|item| merge_patch_templates(item, templates)),
+        JsonValue::Object(map) => map
+            .values()
+            .for_each(|v| merge_patch_templates(v, templates)),
+        _ => {}
+    }
+}
+
+// Applies a merge patch following RFC 7386: objects are merged recursively, a null
+// removes the key and anything else, arrays included, replaces the target value.
+fn json_merge_patch(target: &mut JsonValue, patch: &JsonValue) {
+    let JsonValue::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = JsonValue::Object(serde_json::Map::new());
+    }
+    if let JsonValue::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                json_merge_patch(target.entry(key.clone()).or_insert(JsonValue::Null), value);
+            }
+        }
+    }
+}
+
+fn merge_body(
+    env: &Environment<'static>,
+    ctx: &minijinja::Value,
+    mut target: JsonValue,
+    patch: &JsonValue,
+    parsed_body_as_json: bool,
+) -> Result<Vec<u8>> {
+    let patch = render_merge_patch(env, ctx, patch, parsed_body_as_json)?;
+    json_merge_patch(&mut target, &patch);
+    Ok(serde_json::to_vec(&target)?)
+}
+
+// Returns false when the transform lists methods and the request method isn't one of them
+fn method_matches(
+    transform: &LocalTransform,
+    request_headers_map: &HashMap<String, String>,
+) -> bool {
+    transform.methods.is_empty()
+        || request_headers_map.get(":method").is_some_and(|method| {
+            transform
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(method))
+        })
+}
+
+// Returns true if the transform has no condition or if its condition is true. The condition
+// is rendered with the headers only, before the body is parsed, see validate_condition().
+fn condition_holds(
+    env: &Environment<'static>,
+    transform: &LocalTransform,
+    template_key: &str,
+    ctx: &minijinja::Value,
+) -> Result<bool> {
+    let Some(condition) = &transform.condition else {
+        return Ok(true);
+    };
+    let rendered = render(env, ctx, template_key, condition, false)
+        .context("error rendering the condition, skipping the transformation")?;
+    Ok(!rendered.is_empty())
+}
+
+// The condition is an expression, it is wrapped in a template rendering something only
+// when it is true
+fn condition_template(condition: &str) -> String {
+    format!("{{% if {condition} %}}true{{% endif %}}")
+}
+
+// Renders the dynamic metadata templates, a value rendered empty is not written. Like for
+// the headers, a render error is collected and the other entries are still rendered,
+// except for the undeclared json variables that abort the transformation.
+fn render_dynamic_metadata<T: TransformationOps>(
+    env: &Environment<'static>,
+    ctx: &minijinja::Value,
+    dynamic_metadata: &[DynamicMetadata],
+    parsed_body_as_json: bool,
+    ops: &mut T,
+    errors: &mut Vec<anyhow::Error>,
+) -> Result<()> {
+    for DynamicMetadata {
+        namespace,
+        key,
+        value,
+    } in dynamic_metadata
+    {
+        match render(env, ctx, value, value, parsed_body_as_json) {
+            Ok(rendered) if rendered.is_empty() => {}
+            Ok(rendered) => {
+                ops.set_dynamic_metadata(namespace, key, &rendered);
+            }
+            Err(err) => {
+                ops.increment_stat(TransformationStat::RenderError);
+                if err.downcast_ref::<TransformationError>().is_some() {
+                    return Err(err);
+                }
+                errors.push(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Returns the names of the headers to remove, the templated ones rendered. A name rendered
+// empty removes nothing. The render errors are handled like in render_dynamic_metadata().
+fn render_removals<T: TransformationOps>(
+    env: &Environment<'static>,
+    ctx: &minijinja::Value,
+    transform: &LocalTransform,
+    parsed_body_as_json: bool,
+    ops: &mut T,
+    errors: &mut Vec<anyhow::Error>,
+) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for removal in &transform.remove {
+        let Some(name) = removal.name() else {
+            continue;
+        };
+        let literal = removal.template().is_none();
+        match render_header(env, ctx, name, literal, parsed_body_as_json, ops) {
+            Ok(rendered) if rendered.trim().is_empty() => {}
+            Ok(rendered) => names.push(rendered.trim().to_string()),
+            Err(err) => {
+                ops.increment_stat(TransformationStat::RenderError);
+                if err.downcast_ref::<TransformationError>().is_some() {
+                    return Err(err);
+                }
+                errors.push(err.context(format!("remove {name}")));
+            }
+        }
+    }
+    Ok(names)
+}
+
+// Copies the body fields the json pointers point to into the dynamic metadata. A string
+// is copied as is, any other json value is copied as its json representation.
+fn set_metadata_from_body<T: TransformationOps>(
+    ops: &mut T,
+    metadata_from_body: &[MetadataFromBody],
+    json_body: &JsonValue,
+) {
+    for MetadataFromBody {
+        namespace,
+        key,
+        json_pointer,
+    } in metadata_from_body
+    {
+        match json_body.pointer(json_pointer) {
+            Some(JsonValue::String(value)) => {
+                ops.set_dynamic_metadata(namespace, key, value);
+            }
+            Some(value) => {
+                ops.set_dynamic_metadata(namespace, key, &value.to_string());
+            }
+            None => ops.log_debug(&format!(
+                "{json_pointer} not found in body, skipping dynamic metadata {namespace}/{key}"
+            )),
+        }
+    }
+}
+
+// Called only when the body is rewritten, so a transform that only parses the body
+// leaves Content-Length alone
+fn update_request_content_length<T: TransformationOps>(
+    ops: &mut T,
+    body_transform: &BodyTransform,
+    body_len: usize,
+) {
+    if body_transform.recalculate_content_length {
+        ops.set_request_header("content-length", body_len.to_string().as_bytes());
+    } else {
+        ops.remove_request_header("content-length");
+    }
+}
+
+fn update_response_content_length<T: TransformationOps>(
+    ops: &mut T,
+    body_transform: &BodyTransform,
+    body_len: usize,
+) {
+    if body_transform.recalculate_content_length {
+        ops.set_response_header("content-length", body_len.to_string().as_bytes());
+    } else {
+        ops.remove_response_header("content-length");
+    }
+}
+
+// Runs the extractors against the headers, see Extractor. The headers map has the values
+// after the replace modes were applied, see replace_headers().
+fn extract(
+    extractors: &HashMap<String, Extractor>,
+    headers_map: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    extractors
+        .iter()
+        .map(|(name, extractor)| {
+            let value = headers_map.get(&extractor.header.to_ascii_lowercase());
+            let extracted = match extractor.mode {
+                ExtractionMode::Extract => value
+                    .and_then(|value| extractor.regex.full_match.captures(value))
+                    .and_then(|captures| captures.get(extractor.subgroup))
+                    .map(|m| m.as_str().to_string()),
+                ExtractionMode::SingleReplace | ExtractionMode::ReplaceAll => value.cloned(),
+            };
+            (name.clone(), extracted.unwrap_or_default())
+        })
+        .collect()
+}
+
+// Applies the replace mode extractors, sorted by name, to the headers. Returns the headers
+// with the new values and the names of the headers that changed, None if none did.
+fn replace_headers(
+    extractors: &HashMap<String, Extractor>,
+    headers_map: &HashMap<String, String>,
+) -> Option<(HashMap<String, String>, Vec<String>)> {
+    let mut replacers: Vec<_> = extractors
+        .iter()
+        .filter(|(_, e)| e.mode != ExtractionMode::Extract)
+        .collect();
+    if replacers.is_empty() {
+        return None;
+    }
+    replacers.sort_by_key(|(name, _)| *name);
+    let mut headers = headers_map.clone();
+    let mut replaced = Vec::new();
+    for (_, extractor) in replacers {
+        let header = extractor.header.to_ascii_lowercase();
+        let Some(value) = headers.get(&header) else {
+            continue;
+        };
+        let replacement =
+            to_regex_replacement(extractor.replacement_text.as_deref().unwrap_or_default());
+        let new_value = match extractor.mode {
+            ExtractionMode::ReplaceAll => extractor.regex.regex.replace_all(value, &replacement),
+            _ => extractor.regex.regex.replace(value, &replacement),
+        };
+        if new_value == *value {
+            continue;
+        }
+        let new_value = new_value.into_owned();
+        if !replaced.contains(&header) {
+            replaced.push(header.clone());
+        }
+        headers.insert(header, new_value);
+    }
+    (!replaced.is_empty()).then_some((headers, replaced))
+}
+
+// Converts the `\1` capture group references of the C++ extractors to the `${1}` syntax
+// of the regex crate. A `$` is escaped so it is not taken for a reference.
+fn to_regex_replacement(text: &str) -> String {
+    let mut replacement = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek().is_some_and(|c| c.is_ascii_digit()) => {
+                replacement.push_str("${");
+                while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                    replacement.push(digit);
+                }
+                replacement.push('}');
+            }
+            '$' => replacement.push_str("$$"),
+            c => replacement.push(c),
+        }
+    }
+    replacement
+}
+
+// Returns the headers to set for the copy_prefix operations, e.g. `x-orig-host` for `host`
+// with an empty from_prefix and a `x-orig-` to_prefix. They are sorted by name so they are
+// always set in the same order.
+fn copy_prefix_headers<'a>(
+    copy_prefix: &[CopyPrefix],
+    headers_map: &'a HashMap<String, String>,
+) -> Vec<(String, &'a str)> {
+    let mut headers = Vec::new();
+    for CopyPrefix {
+        from_prefix,
+        to_prefix,
+    } in copy_prefix
+    {
+        let from_prefix = from_prefix.to_ascii_lowercase();
+        let mut copied: Vec<_> = headers_map
+            .iter()
+            .filter_map(|(name, value)| {
+                let suffix = name.strip_prefix(&from_prefix)?;
+                Some((format!("{to_prefix}{suffix}"), value.as_str()))
+            })
+            .collect();
+        copied.sort();
+        headers.extend(copied);
+    }
+    headers
+}
+
+// Returns the headers to remove for the remove prefixes, globs and regexes. The pseudo
+// headers are never matched. The headers map is a snapshot of the headers received, so the
+// headers can be removed while going through it.
+fn remove_matching_headers<'a>(
+    transform: &LocalTransform,
+    headers_map: &'a HashMap<String, String>,
+) -> Vec<&'a str> {
+    let removals: Vec<_> = transform
+        .remove
+        .iter()
+        .filter(|removal| removal.name().is_none())
+        .collect();
+    if removals.is_empty() {
+        return Vec::new();
+    }
+    let mut headers: Vec<_> = headers_map
+        .keys()
+        .filter(|name| removals.iter().any(|removal| removal.matches(name)))
+        .map(String::as_str)
+        .collect();
+    headers.sort();
+    headers
+}
+
+// Returns true if the Content-Type matches one of the content_type_matches patterns,
+// always true when there are none
+fn content_type_matches(patterns: &[String], headers_map: &HashMap<String, String>) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+    let Some(media_type) = headers_map
+        .get("content-type")
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty())
+    else {
+        return false;
+    };
+    patterns
+        .iter()
+        .any(|p| glob_match(&p.trim().to_ascii_lowercase(), &media_type))
+}
+
+// Returns why the body can't be transformed as is, e.g. it's gzip encoded or binary.
+// The body is only fetched when the transform would use it.
+fn body_skip_reason(
+    body_transform: &BodyTransform,
+    headers_map: &HashMap<String, String>,
+    get_body: impl FnOnce() -> Vec<u8>,
+) -> Option<String> {
+    // Unless it is parsed for the header templates, a removed body is never looked at
+    if body_transform.remove_body && matches!(body_transform.parse_as, BodyParseBehavior::AsString)
+    {
+        return None;
+    }
+    if let Some(encoding) = headers_map
+        .get("content-encoding")
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty() && !v.eq_ignore_ascii_case("identity"))
+    {
+        return Some(format!("{encoding} encoded"));
+    }
+    if body_transform.is_empty() || body_transform.treat_body_as_bytes {
+        return None;
+    }
+    if std::str::from_utf8(&get_body()).is_err() {
+        return Some("not valid UTF-8".to_string());
+    }
+    None
+}
+
+// Names the header of a header template error, along with the transform if it has a name
+fn header_error_context(transform: &LocalTransform, key: &str) -> String {
+    match &transform.name {
+        Some(name) => format!("transform '{name}' header '{key}'"),
+        None => format!("header '{key}'"),
+    }
+}
+
+fn combine_errors(msg: &str, transform: &LocalTransform, errors: Vec<Error>) -> Result<()> {
+    // Each error can have multiple level of errors, that's why there is
+    // the e.chain() iterating through each error and combine them
+    if !errors.is_empty() {
+        let combined = errors
+            .into_iter()
+            .map(|e| {
+                e.chain()
+                    .map(|cause| cause.to_string())
+                    .collect::<Vec<String>>()
+                    .join(":")
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        return Err(match &transform.name {
+            Some(name) => anyhow::anyhow!("{msg} transform '{name}': {combined}"),
+            None => anyhow::anyhow!("{msg}: {combined}"),
+        });
+    }
+
+    Ok(())
+}
+
+// Information about the stream the templates can use besides the headers and the body
+#[derive(Default)]
+pub struct StreamInfo<'a> {
+    // The name of the matched route, exposed as route_name
+    pub route_name: &'a str,
+    // The downstream remote address, read by source_ip()
+    pub source_address: &'a str,
+    // The TLS SNI and client certificate subject of the downstream connection, read by
+    // tls_sni() and client_cert_subject(). Both are empty for a plaintext connection.
+    pub tls_sni: &'a str,
+    pub client_cert_subject: &'a str,
+    // The request body, only used by the response transform
+    pub request_body: Option<&'a [u8]>,
+    // The seed of replace_with_random(), see randomSeedHeader
+    pub random_seed: Option<u64>,
+    // The filter state values read by filter_state(), see filter_state_keys()
+    pub filter_state: Option<&'a HashMap<String, String>>,
+    // The number of values of each request header, read by header_count()
+    pub header_counts: Option<&'a HashMap<String, usize>>,
+    // All the values of each header, read by header_values(). The request headers for the
+    // request transform and the response headers for the response one.
+    pub header_values: Option<&'a HashMap<String, Vec<String>>>,
+    // The raw value of each header, read by header_bytes_base64(). The request or response
+    // headers like header_values.
+    pub header_bytes: Option<&'a HashMap<String, Vec<u8>>>,
+    // The X-Forwarded-For values of the original request, read by client_ip_from_xff()
+    pub forwarded_for: Option<&'a [String]>,
+}
+
+fn encode_header_bytes(header_bytes: &HashMap<String, Vec<u8>>) -> HashMap<&str, String> {
+    header_bytes
+        .iter()
+        .map(|(key, value)| (key.as_str(), STANDARD.encode(value)))
+        .collect()
+}
+
+// A body chunk as envoy received it, for the streaming body transforms
+pub struct BodyChunk<'a> {
+    pub data: &'a [u8],
+    pub index: usize,
+    pub end_of_stream: bool,
+}
+
+/// Transform Request
+///
+/// On any header rendering errors, we will remove the header and continue
+/// All the errors are collected and bubble up the chain so they can be logged
+/// On body parsing as json error, we return error immediately so we can send a
+/// 400 response back
+pub fn transform_request<T: TransformationOps>(
+    env: &Environment<'static>,
+    transform: &LocalTransform,
+    request_headers_map: &HashMap<String, String>,
+    stream_info: &StreamInfo,
+    mut ops: T,
+) -> Result<()> {
+    if !method_matches(transform, request_headers_map) {
+        ops.log_debug("the method doesn't match, skipping the request transformation");
+        return Ok(());
+    }
+    let mut errors = Vec::new();
+    let strict = matches!(env.undefined_behavior(), UndefinedBehavior::Strict);
+
+    // the headers are rewritten by the replace mode extractors before anything else, so
+    // the templates see the new values
+    let replaced_headers = replace_headers(&transform.extractors, request_headers_map);
+    // Set when the header operations change a request header, for clearRouteCache
+    let mut headers_changed = replaced_headers.is_some();
+    let request_headers_map = match &replaced_headers {
+        Some((headers, _)) => headers,
+        None => request_headers_map,
+    };
+
+    // for request rendering, both the header() and request_header() use the request_headers
+    let mut m = stream_context(request_headers_map, None, stream_info);
+    if !transform.extractors.is_empty() {
+        m.insert(
+            STATE_LOOKUP_KEY_EXTRACTIONS.to_string(),
+            minijinja::Value::from_serialize(extract(&transform.extractors, request_headers_map)),
+        );
+    }
+    if !condition_holds(
+        env,
+        transform,
+        REQUEST_CONDITION_TEMPLATE_LOOKUP_KEY,
+        &minijinja::Value::from(m.clone()),
+    )
+    .inspect_err(|_| ops.increment_stat(TransformationStat::RenderError))?
+    {
+        ops.log_debug("the condition is false, skipping the request transformation");
+        return Ok(());
+    }
+    ops.increment_stat(TransformationStat::Transformation);
+    if let Some((headers, replaced)) = &replaced_headers {
+        for key in replaced {
+            ops.set_request_header(key, headers[key].as_bytes());
+            ops.increment_stat(TransformationStat::HeaderSet);
+        }
+    }
     let mut parsed_body_as_json = false;
-    if let Some(body_transform) = transform.body.as_ref() {
+    let mut merge_target = None;
+    // a streamed body is transformed chunk by chunk instead, see render_chunk()
+    let mut body_transform = transform.body_transform().filter(|c| !c.streaming);
+    if let Some(bt) = body_transform {
+        if !content_type_matches(&bt.content_type_matches, request_headers_map) {
+            ops.log_debug("skipping the body transformation, the content type doesn't match");
+            parsed_body_as_json = matches!(bt.parse_as, BodyParseBehavior::AsJson);
+            body_transform = None;
+        } else if let Some(reason) =
+            body_skip_reason(bt, request_headers_map, || ops.get_request_body())
+        {
+            errors.push(anyhow::anyhow!(
+                "skipping the body transformation, the body is {reason}"
+            ));
+            // the json fields are not available but that's not the client's fault
+            parsed_body_as_json = matches!(bt.parse_as, BodyParseBehavior::AsJson);
+            body_transform = None;
+        }
+    }
+    if let Some(body_transform) = body_transform {
         if matches!(body_transform.parse_as, BodyParseBehavior::AsJson) {
-            let json_body = ops.parse_request_json_body()?;
+            let json_body = match ops.parse_request_json_body() {
+                Ok(json_body) => {
+                    if body_transform.merge.is_some() {
+                        merge_target = Some(json_body.clone());
+                    }
+                    json_body
+                }
+                Err(err) if body_transform.ignore_error_on_parse => {
+                    ops.log_debug(&format!("ignoring the body parsing error: {err:#}"));
+                    // the json fields are left undefined and render empty
+                    parsed_body_as_json = true;
+                    JsonValue::Null
+                }
+                Err(err) => return Err(err),
+            };
+            set_metadata_from_body(&mut ops, &transform.metadata_from_body, &json_body);
 
             if json_body != JsonValue::Null {
                 if body_transform.value.contains("context()") {
@@ -310,18 +1806,32 @@ pub fn transform_request<T: TransformationOps>(
                     );
                 }
 
-                if let JsonValue::Object(map) = json_body {
+                if let JsonValue::Object(map) = &json_body {
                     for (k, v) in map {
-                        m.insert(k, minijinja::Value::from_serialize(&v));
+                        m.insert(k.clone(), minijinja::Value::from_serialize(v));
                     }
                 }
+                m.insert(
+                    CONTEXT_KEY_PARSED_BODY.to_string(),
+                    JsonBody::new_value(json_body),
+                );
 
                 parsed_body_as_json = true;
             }
         }
     }
 
-    if let Some(body_transform) = transform.body.as_ref() {
+    if let Some(body_transform) = body_transform {
+        if matches!(body_transform.parse_as, BodyParseBehavior::AsFormUrlEncoded) {
+            let form = form::parse_form_urlencoded(&ops.get_request_body());
+            m.insert(
+                CONTEXT_KEY_FORM.to_string(),
+                minijinja::Value::from_serialize(&form),
+            );
+        }
+    }
+
+    if let Some(body_transform) = body_transform {
         if body_transform.value.contains("body()") {
             let body = ops.get_request_body();
             m.insert(
@@ -329,13 +1839,23 @@ pub fn transform_request<T: TransformationOps>(
                 minijinja::Value::from_serialize(String::from_utf8_lossy(&body)),
             );
         }
+        if body_transform.value.contains("body_base64()") {
+            m.insert(
+                STATE_LOOKUP_KEY_BODY_BASE64.to_string(),
+                minijinja::Value::from(STANDARD.encode(ops.get_request_body())),
+            );
+        }
     }
 
-    let ctx = minijinja::Value::from(m);
+    let mut sequential = transform
+        .sequential_set
+        .then(|| (m.clone(), request_headers_map.clone()));
+    let mut ctx = minijinja::Value::from(m);
 
-    if let Some(body_transform) = transform.body.as_ref() {
+    // counted once, whether the body is replaced, merged with a patch or removed
+    let mut body_transformed = false;
+    if let Some(body_transform) = body_transform {
         if !body_transform.value.is_empty() {
-            ops.drain_request_body(u64::MAX.try_into().unwrap());
             let rendered = match render(
                 env,
                 &ctx,
@@ -345,94 +1865,255 @@ pub fn transform_request<T: TransformationOps>(
             ) {
                 Ok(str) => Some(str),
                 Err(e) => {
+                    ops.increment_stat(TransformationStat::RenderError);
                     errors.push(e);
                     None
                 }
             };
-            if rendered.as_deref().is_some_and(|s| !s.is_empty()) {
-                let rendered_body = rendered.as_deref().unwrap().as_bytes();
-                ops.set_request_header(
-                    "content-length",
-                    rendered_body.len().to_string().as_bytes(),
-                );
-                ops.append_request_body(rendered_body);
-            } else {
-                ops.set_request_header("content-length", b"0");
-                // In classic transformation, we remove content-type only when "passthrough_body"
-                // is set to true (even the body is not transformed but it comes in as 0 bytes)
-                // Here, we are only removing content-type if we have an override that ended up
-                // removing the body as we don't have passthrough_body setting in kgateway
-                ops.remove_request_header("content-type");
+            // In strict mode, a body that failed to render is left untouched
+            if rendered.is_some() || !strict {
+                let rendered_body = rendered.as_deref().unwrap_or_default().as_bytes();
+                update_request_content_length(&mut ops, body_transform, rendered_body.len());
+                ops.set_request_body(rendered_body);
+                body_transformed = true;
+                if rendered_body.is_empty() {
+                    // In classic transformation, we remove content-type only when "passthrough_body"
+                    // is set to true (even the body is not transformed but it comes in as 0 bytes)
+                    // Here, we are only removing content-type if we have an override that ended up
+                    // removing the body as we don't have passthrough_body setting in kgateway
+                    ops.remove_request_header("content-type");
+                } else if let Some(content_type) = &body_transform.content_type {
+                    ops.set_request_header("content-type", content_type.as_bytes());
+                }
+            }
+        }
+    }
+
+    if let Some(body_transform) = body_transform {
+        if let Some(patch) = &body_transform.merge {
+            // A body that is not a json object is left untouched, the patch would otherwise
+            // replace it as a whole. A patch that failed to render is an error.
+            let target = match merge_target {
+                Some(json) => Ok(json),
+                None => ops.parse_request_json_body(),
+            };
+            match target {
+                Ok(target) if target.is_object() => {
+                    match merge_body(env, &ctx, target, patch, parsed_body_as_json) {
+                        Ok(merged_body) => {
+                            update_request_content_length(
+                                &mut ops,
+                                body_transform,
+                                merged_body.len(),
+                            );
+                            ops.set_request_body(&merged_body);
+                            body_transformed = true;
+                        }
+                        Err(e) => errors.push(e),
+                    }
+                }
+                Ok(_) => ops.log_debug("the body is not a json object, skipping the merge patch"),
+                Err(err) => ops.log_debug(&format!(
+                    "the body is not json, skipping the merge patch: {err:#}"
+                )),
             }
         }
     }
 
+    if let Some(body_transform) = body_transform.filter(|c| c.remove_body) {
+        ops.drain_request_body(usize::MAX);
+        ops.remove_request_header("transfer-encoding");
+        ops.remove_request_header("content-type");
+        update_request_content_length(&mut ops, body_transform, 0);
+        body_transformed = true;
+    }
+    if body_transformed {
+        ops.increment_stat(TransformationStat::BodyTransform);
+    }
+
+    // the streamed body is rewritten chunk by chunk, so its length is not known up front
+    if transform.streaming_body_transform().is_some() {
+        ops.remove_request_header("content-length");
+    }
+
+    for (key, value) in copy_prefix_headers(&transform.copy_prefix, request_headers_map) {
+        headers_changed |= request_headers_map
+            .get(&key.to_lowercase())
+            .map(String::as_str)
+            != Some(value);
+        ops.set_request_header(&key, value.as_bytes());
+        ops.increment_stat(TransformationStat::HeaderSet);
+    }
+
     let mut abort_processing = false;
-    for NameValuePair { name: key, value } in &transform.set {
+    for NameValuePair {
+        name: key,
+        value,
+        max_value_bytes,
+        literal,
+        if_present,
+    } in &transform.set
+    {
         if value.is_empty() {
             // This is following the classic transformation filter behavior
+            headers_changed |= request_headers_map.contains_key(&key.to_lowercase());
             ops.remove_request_header(key);
+            ops.increment_stat(TransformationStat::HeaderRemoved);
+            update_sequential_headers(&mut sequential, REQUEST_HEADER_KEYS, key, None, &mut ctx);
             continue;
         }
-        let rendered = match render(env, &ctx, value, value, parsed_body_as_json) {
-            Ok(str) => Some(str),
-            Err(err) => {
-                if let Some(e) = err.downcast_ref::<TransformationError>() {
-                    match e {
-                        TransformationError::UndeclaredJsonVariables(_) => {
-                            abort_processing = true;
+        let rendered =
+            match render_header(env, &ctx, value, *literal, parsed_body_as_json, &mut ops) {
+                Ok(str) => Some(str),
+                Err(err) => {
+                    if let Some(e) = err.downcast_ref::<TransformationError>() {
+                        match e {
+                            TransformationError::UndeclaredJsonVariables(_) => {
+                                abort_processing = true;
+                            }
                         }
                     }
+                    ops.increment_stat(TransformationStat::RenderError);
+                    errors.push(err.context(header_error_context(transform, key)));
+                    None
                 }
-                errors.push(err);
-                None
-            }
-        };
+            };
 
         if abort_processing {
             return Err(errors.pop().unwrap());
         }
+        let rendered = rendered.map(|value| {
+            limit_header_value(
+                &ops,
+                key,
+                value,
+                max_value_bytes.or(transform.max_header_value_bytes),
+                transform.oversized_header_value,
+            )
+        });
 
         if rendered.as_deref().is_some_and(|s| !s.is_empty()) {
+            headers_changed |= request_headers_map.get(&key.to_lowercase()) != rendered.as_ref();
             ops.set_request_header(key, rendered.as_deref().unwrap().as_bytes());
-        } else {
+            ops.increment_stat(TransformationStat::HeaderSet);
+            update_sequential_headers(
+                &mut sequential,
+                REQUEST_HEADER_KEYS,
+                key,
+                rendered.as_deref(),
+                &mut ctx,
+            );
+        } else if (rendered.is_some() && !if_present) || (rendered.is_none() && !strict) {
+            // With ifPresent, a header that rendered empty is left untouched. In strict
+            // mode, so is a header that failed to render.
+            headers_changed |= request_headers_map.contains_key(&key.to_lowercase());
             ops.remove_request_header(key);
+            ops.increment_stat(TransformationStat::HeaderRemoved);
+            update_sequential_headers(&mut sequential, REQUEST_HEADER_KEYS, key, None, &mut ctx);
         }
     }
 
-    for NameValuePair { name: key, value } in &transform.add {
+    for NameValuePair {
+        name: key,
+        value,
+        max_value_bytes,
+        literal,
+        ..
+    } in &transform.add
+    {
         if value.is_empty() {
             continue;
         }
-        let rendered = match render(env, &ctx, value, value, parsed_body_as_json) {
-            Ok(str) => Some(str),
-            Err(err) => {
-                if let Some(e) = err.downcast_ref::<TransformationError>() {
-                    match e {
-                        TransformationError::UndeclaredJsonVariables(_) => {
-                            abort_processing = true;
+        let rendered =
+            match render_header(env, &ctx, value, *literal, parsed_body_as_json, &mut ops) {
+                Ok(str) => Some(str),
+                Err(err) => {
+                    if let Some(e) = err.downcast_ref::<TransformationError>() {
+                        match e {
+                            TransformationError::UndeclaredJsonVariables(_) => {
+                                abort_processing = true;
+                            }
                         }
                     }
+                    ops.increment_stat(TransformationStat::RenderError);
+                    errors.push(err.context(header_error_context(transform, key)));
+                    None
                 }
-                errors.push(err);
-                None
-            }
-        };
+            };
 
         if abort_processing {
             return Err(errors.pop().unwrap());
         }
+        let rendered = rendered.map(|value| {
+            limit_header_value(
+                &ops,
+                key,
+                value,
+                max_value_bytes.or(transform.max_header_value_bytes),
+                transform.oversized_header_value,
+            )
+        });
 
         if rendered.as_deref().is_some_and(|s| !s.is_empty()) {
+            headers_changed = true;
             ops.add_request_header(key, rendered.as_deref().unwrap().as_bytes());
+            ops.increment_stat(TransformationStat::HeaderSet);
+        }
+    }
+
+    if let Some(host) = &transform.host_rewrite {
+        match render_header(env, &ctx, host, false, parsed_body_as_json, &mut ops) {
+            Ok(rendered) if rendered.trim().is_empty() => {}
+            Ok(rendered) => {
+                let host = rendered.trim();
+                headers_changed |=
+                    request_headers_map.get(":authority").map(String::as_str) != Some(host);
+                ops.set_request_header(":authority", host.as_bytes());
+                if request_headers_map.contains_key("host") {
+                    ops.set_request_header("host", host.as_bytes());
+                }
+                ops.increment_stat(TransformationStat::HeaderSet);
+            }
+            Err(err) => {
+                ops.increment_stat(TransformationStat::RenderError);
+                errors.push(err.context("hostRewrite"));
+            }
         }
     }
 
-    for key in &transform.remove {
+    render_dynamic_metadata(
+        env,
+        &ctx,
+        &transform.dynamic_metadata,
+        parsed_body_as_json,
+        &mut ops,
+        &mut errors,
+    )?;
+
+    for key in render_removals(
+        env,
+        &ctx,
+        transform,
+        parsed_body_as_json,
+        &mut ops,
+        &mut errors,
+    )? {
+        headers_changed |= request_headers_map.contains_key(&key.to_lowercase());
+        ops.remove_request_header(&key);
+        ops.increment_stat(TransformationStat::HeaderRemoved);
+    }
+
+    for key in remove_matching_headers(transform, request_headers_map) {
+        headers_changed = true;
         ops.remove_request_header(key);
+        ops.increment_stat(TransformationStat::HeaderRemoved);
+    }
+
+    if transform.clear_route_cache && headers_changed {
+        ops.clear_route_cache();
     }
 
-    combine_errors("transform_request()", errors)
+    combine_errors("transform_request()", transform, errors)
 }
 
 /// Transform Response
@@ -446,25 +2127,100 @@ pub fn transform_response<T: TransformationOps>(
     transform: &LocalTransform,
     request_headers_map: &HashMap<String, String>,
     response_headers_map: &HashMap<String, String>,
+    stream_info: &StreamInfo,
     mut ops: T,
 ) -> Result<()> {
+    if !method_matches(transform, request_headers_map) {
+        ops.log_debug("the method doesn't match, skipping the response transformation");
+        return Ok(());
+    }
     let mut errors = Vec::new();
+    let strict = matches!(env.undefined_behavior(), UndefinedBehavior::Strict);
 
-    let mut m = BTreeMap::new();
-    // for response rendering, header() uses response_headers and request_header()
-    // uses the request_headers. So, setting them in the context accordingly
-    m.insert(
-        STATE_LOOKUP_KEY_HEADERS.to_string(),
-        minijinja::Value::from_serialize(response_headers_map),
-    );
-    m.insert(
-        STATE_LOOKUP_KEY_REQ_HEADERS.to_string(),
-        minijinja::Value::from_serialize(request_headers_map),
-    );
+    let replaced_headers = replace_headers(&transform.extractors, response_headers_map);
+    let response_headers_map = match &replaced_headers {
+        Some((headers, _)) => headers,
+        None => response_headers_map,
+    };
+
+    // for response rendering, header() and response_header() use response_headers and
+    // request_header() uses the request_headers
+    let mut m = stream_context(request_headers_map, Some(response_headers_map), stream_info);
+    if let Some(request_body) = stream_info.request_body {
+        m.insert(
+            CONTEXT_KEY_REQUEST_BODY.to_string(),
+            match serde_json::from_slice::<JsonValue>(request_body) {
+                Ok(json) => minijinja::Value::from_serialize(&json),
+                Err(_) => minijinja::Value::from(String::from_utf8_lossy(request_body)),
+            },
+        );
+    }
+    if !transform.extractors.is_empty() {
+        m.insert(
+            STATE_LOOKUP_KEY_EXTRACTIONS.to_string(),
+            minijinja::Value::from_serialize(extract(&transform.extractors, response_headers_map)),
+        );
+    }
+    if !condition_holds(
+        env,
+        transform,
+        RESPONSE_CONDITION_TEMPLATE_LOOKUP_KEY,
+        &minijinja::Value::from(m.clone()),
+    )
+    .inspect_err(|_| ops.increment_stat(TransformationStat::RenderError))?
+    {
+        ops.log_debug("the condition is false, skipping the response transformation");
+        return Ok(());
+    }
+    ops.increment_stat(TransformationStat::Transformation);
+    if let Some((headers, replaced)) = &replaced_headers {
+        for key in replaced {
+            ops.set_response_header(key, headers[key].as_bytes());
+            ops.increment_stat(TransformationStat::HeaderSet);
+        }
+    }
     let mut parsed_body_as_json = false;
-    if let Some(body_transform) = transform.body.as_ref() {
+    let mut merge_target = None;
+    // a streamed body is transformed chunk by chunk instead, see render_chunk()
+    let mut body_transform = transform.body_transform().filter(|c| !c.streaming);
+    if let Some(bt) = body_transform {
+        if !bt.matches_status(response_headers_map.get(":status").map(String::as_str)) {
+            ops.log_debug("skipping the body transformation, the status doesn't match");
+            parsed_body_as_json = matches!(bt.parse_as, BodyParseBehavior::AsJson);
+            body_transform = None;
+        } else if !content_type_matches(&bt.content_type_matches, response_headers_map) {
+            ops.log_debug("skipping the body transformation, the content type doesn't match");
+            parsed_body_as_json = matches!(bt.parse_as, BodyParseBehavior::AsJson);
+            body_transform = None;
+        } else if let Some(reason) =
+            body_skip_reason(bt, response_headers_map, || ops.get_response_body())
+        {
+            errors.push(anyhow::anyhow!(
+                "skipping the body transformation, the body is {reason}"
+            ));
+            // the json fields are not available but that's not the client's fault
+            parsed_body_as_json = matches!(bt.parse_as, BodyParseBehavior::AsJson);
+            body_transform = None;
+        }
+    }
+    if let Some(body_transform) = body_transform {
         if matches!(body_transform.parse_as, BodyParseBehavior::AsJson) {
-            let json_body = ops.parse_response_json_body()?;
+            let json_body = match ops.parse_response_json_body() {
+                Ok(json_body) => {
+                    if body_transform.merge.is_some() {
+                        merge_target = Some(json_body.clone());
+                    }
+                    json_body
+                }
+                Err(err) if body_transform.ignore_error_on_parse => {
+                    ops.log_debug(&format!("ignoring the body parsing error: {err:#}"));
+                    // the json fields are left undefined and render empty
+                    parsed_body_as_json = true;
+                    JsonValue::Null
+                }
+                Err(err) => return Err(err),
+            };
+            set_metadata_from_body(&mut ops, &transform.metadata_from_body, &json_body);
 
             if json_body != JsonValue::Null {
                 if body_transform.value.contains("context()") {
@@ -474,17 +2230,31 @@ pub fn transform_response<T: TransformationOps>(
                     );
                 }
 
-                if let JsonValue::Object(map) = json_body {
+                if let JsonValue::Object(map) = &json_body {
                     for (k, v) in map {
-                        m.insert(k, minijinja::Value::from_serialize(&v));
+                        m.insert(k.clone(), minijinja::Value::from_serialize(v));
                     }
                 }
+                m.insert(
+                    CONTEXT_KEY_PARSED_BODY.to_string(),
+                    JsonBody::new_value(json_body),
+                );
                 parsed_body_as_json = true;
             }
         }
     }
 
-    if let Some(body_transform) = transform.body.as_ref() {
+    if let Some(body_transform) = body_transform {
+        if matches!(body_transform.parse_as, BodyParseBehavior::AsFormUrlEncoded) {
+            let form = form::parse_form_urlencoded(&ops.get_response_body());
+            m.insert(
+                CONTEXT_KEY_FORM.to_string(),
+                minijinja::Value::from_serialize(&form),
+            );
+        }
+    }
+
+    if let Some(body_transform) = body_transform {
         if body_transform.value.contains("body()") {
             let body = ops.get_response_body();
             m.insert(
@@ -492,16 +2262,23 @@ pub fn transform_response<T: TransformationOps>(
                 minijinja::Value::from_serialize(String::from_utf8_lossy(&body)),
             );
         }
+        if body_transform.value.contains("body_base64()") {
+            m.insert(
+                STATE_LOOKUP_KEY_BODY_BASE64.to_string(),
+                minijinja::Value::from(STANDARD.encode(ops.get_response_body())),
+            );
+        }
     }
 
-    let ctx = minijinja::Value::from(m);
+    let mut sequential = transform
+        .sequential_set
+        .then(|| (m.clone(), response_headers_map.clone()));
+    let mut ctx = minijinja::Value::from(m);
 
-    if let Some(body_transform) = transform.body.as_ref() {
+    // counted once, whether the body is replaced, merged with a patch or removed
+    let mut body_transformed = false;
+    if let Some(body_transform) = body_transform {
         if !body_transform.value.is_empty() {
-            // The envoy sdk function would drain all the bytes if the number passed in is greater
-            // than the content length. This is to avoid having to iterate through the buffer to
-            // calculate the size.
-            ops.drain_response_body(u64::MAX.try_into().unwrap());
             let rendered = match render(
                 env,
                 &ctx,
@@ -511,137 +2288,1041 @@ pub fn transform_response<T: TransformationOps>(
             ) {
                 Ok(str) => Some(str),
                 Err(e) => {
+                    ops.increment_stat(TransformationStat::RenderError);
                     errors.push(e);
                     None
                 }
             };
-            if rendered.as_deref().is_some_and(|s| !s.is_empty()) {
-                let rendered_body = rendered.as_deref().unwrap().as_bytes();
-                ops.set_response_header(
-                    "content-length",
-                    rendered_body.len().to_string().as_bytes(),
-                );
-                ops.append_response_body(rendered_body);
-            } else {
-                ops.set_response_header("content-length", b"0");
-                // In classic transformation, we remove content-type only when "passthrough_body"
-                // is set to true (even the body is not transformed but it comes in as 0 bytes)
-                // Here, we are only removing content-type if we have an override that ended up
-                // removing the body as we don't have passthrough_body setting in kgateway
-                ops.remove_response_header("content-type");
+            // In strict mode, a body that failed to render is left untouched
+            if rendered.is_some() || !strict {
+                let rendered_body = rendered.as_deref().unwrap_or_default().as_bytes();
+                update_response_content_length(&mut ops, body_transform, rendered_body.len());
+                ops.set_response_body(rendered_body);
+                body_transformed = true;
+                if rendered_body.is_empty() {
+                    // In classic transformation, we remove content-type only when "passthrough_body"
+                    // is set to true (even the body is not transformed but it comes in as 0 bytes)
+                    // Here, we are only removing content-type if we have an override that ended up
+                    // removing the body as we don't have passthrough_body setting in kgateway
+                    ops.remove_response_header("content-type");
+                } else if let Some(content_type) = &body_transform.content_type {
+                    ops.set_response_header("content-type", content_type.as_bytes());
+                }
             }
         }
     }
 
-    let mut abort_processing = false;
-    for NameValuePair { name: key, value } in &transform.set {
-        if value.is_empty() {
+    if let Some(body_transform) = body_transform {
+        if let Some(patch) = &body_transform.merge {
+            // A body that is not a json object is left untouched, the patch would otherwise
+            // replace it as a whole. A patch that failed to render is an error.
+            let target = match merge_target {
+                Some(json) => Ok(json),
+                None => ops.parse_response_json_body(),
+            };
+            match target {
+                Ok(target) if target.is_object() => {
+                    match merge_body(env, &ctx, target, patch, parsed_body_as_json) {
+                        Ok(merged_body) => {
+                            update_response_content_length(
+                                &mut ops,
+                                body_transform,
+                                merged_body.len(),
+                            );
+                            ops.set_response_body(&merged_body);
+                            body_transformed = true;
+                        }
+                        Err(e) => errors.push(e),
+                    }
+                }
+                Ok(_) => ops.log_debug("the body is not a json object, skipping the merge patch"),
+                Err(err) => ops.log_debug(&format!(
+                    "the body is not json, skipping the merge patch: {err:#}"
+                )),
+            }
+        }
+    }
+
+    if let Some(body_transform) = body_transform.filter(|c| c.remove_body) {
+        ops.drain_response_body(usize::MAX);
+        ops.remove_response_header("transfer-encoding");
+        ops.remove_response_header("content-type");
+        update_response_content_length(&mut ops, body_transform, 0);
+        body_transformed = true;
+    }
+    if body_transformed {
+        ops.increment_stat(TransformationStat::BodyTransform);
+    }
+
+    // the streamed body is rewritten chunk by chunk, so its length is not known up front
+    if transform.streaming_body_transform().is_some() {
+        ops.remove_response_header("content-length");
+    }
+
+    for (key, value) in copy_prefix_headers(&transform.copy_prefix, response_headers_map) {
+        ops.set_response_header(&key, value.as_bytes());
+        ops.increment_stat(TransformationStat::HeaderSet);
+    }
+
+    let mut abort_processing = false;
+    for NameValuePair {
+        name: key,
+        value,
+        max_value_bytes,
+        literal,
+        if_present,
+    } in &transform.set
+    {
+        if value.is_empty() {
             // This is following the classic transformation filter behavior
             ops.remove_response_header(key);
+            ops.increment_stat(TransformationStat::HeaderRemoved);
+            update_sequential_headers(&mut sequential, RESPONSE_HEADER_KEYS, key, None, &mut ctx);
             continue;
         }
-        let rendered = match render(env, &ctx, value, value, parsed_body_as_json) {
-            Ok(str) => Some(str),
-            Err(err) => {
-                if let Some(e) = err.downcast_ref::<TransformationError>() {
-                    match e {
-                        TransformationError::UndeclaredJsonVariables(_) => {
-                            abort_processing = true;
+        let rendered =
+            match render_header(env, &ctx, value, *literal, parsed_body_as_json, &mut ops) {
+                Ok(str) => Some(str),
+                Err(err) => {
+                    if let Some(e) = err.downcast_ref::<TransformationError>() {
+                        match e {
+                            TransformationError::UndeclaredJsonVariables(_) => {
+                                abort_processing = true;
+                            }
                         }
                     }
+                    ops.increment_stat(TransformationStat::RenderError);
+                    errors.push(err.context(header_error_context(transform, key)));
+                    None
                 }
-                errors.push(err);
-                None
-            }
-        };
+            };
 
         if abort_processing {
             return Err(errors.pop().unwrap());
         }
+        let rendered = rendered.map(|value| {
+            limit_header_value(
+                &ops,
+                key,
+                value,
+                max_value_bytes.or(transform.max_header_value_bytes),
+                transform.oversized_header_value,
+            )
+        });
 
         if rendered.as_deref().is_some_and(|s| !s.is_empty()) {
             ops.set_response_header(key, rendered.as_deref().unwrap().as_bytes());
-        } else {
+            ops.increment_stat(TransformationStat::HeaderSet);
+            update_sequential_headers(
+                &mut sequential,
+                RESPONSE_HEADER_KEYS,
+                key,
+                rendered.as_deref(),
+                &mut ctx,
+            );
+        } else if (rendered.is_some() && !if_present) || (rendered.is_none() && !strict) {
+            // With ifPresent, a header that rendered empty is left untouched. In strict
+            // mode, so is a header that failed to render.
             ops.remove_response_header(key);
+            ops.increment_stat(TransformationStat::HeaderRemoved);
+            update_sequential_headers(&mut sequential, RESPONSE_HEADER_KEYS, key, None, &mut ctx);
         }
     }
 
-    for NameValuePair { name: key, value } in &transform.add {
+    for NameValuePair {
+        name: key,
+        value,
+        max_value_bytes,
+        literal,
+        ..
+    } in &transform.add
+    {
         if value.is_empty() {
             continue;
         }
-        let rendered = match render(env, &ctx, value, value, parsed_body_as_json) {
-            Ok(str) => Some(str),
-            Err(err) => {
-                if let Some(e) = err.downcast_ref::<TransformationError>() {
-                    match e {
-                        TransformationError::UndeclaredJsonVariables(_) => {
-                            abort_processing = true;
+        let rendered =
+            match render_header(env, &ctx, value, *literal, parsed_body_as_json, &mut ops) {
+                Ok(str) => Some(str),
+                Err(err) => {
+                    if let Some(e) = err.downcast_ref::<TransformationError>() {
+                        match e {
+                            TransformationError::UndeclaredJsonVariables(_) => {
+                                abort_processing = true;
+                            }
                         }
                     }
+                    ops.increment_stat(TransformationStat::RenderError);
+                    errors.push(err.context(header_error_context(transform, key)));
+                    None
                 }
-                errors.push(err);
-                None
-            }
-        };
+            };
 
         if abort_processing {
             return Err(errors.pop().unwrap());
         }
+        let rendered = rendered.map(|value| {
+            limit_header_value(
+                &ops,
+                key,
+                value,
+                max_value_bytes.or(transform.max_header_value_bytes),
+                transform.oversized_header_value,
+            )
+        });
 
         if rendered.as_deref().is_some_and(|s| !s.is_empty()) {
             ops.add_response_header(key, rendered.as_deref().unwrap().as_bytes());
+            ops.increment_stat(TransformationStat::HeaderSet);
         }
     }
 
-    for key in &transform.remove {
+    if let Some(status) = &transform.status {
+        match render_header(env, &ctx, status, false, parsed_body_as_json, &mut ops) {
+            Ok(rendered) => match rendered.trim() {
+                "" => {}
+                code if code.parse::<u16>().is_ok_and(|c| (100..=599).contains(&c)) => {
+                    ops.set_response_header(":status", code.as_bytes());
+                }
+                code => errors.push(anyhow::anyhow!(
+                    "status: {code:?} is not a valid status code"
+                )),
+            },
+            Err(err) => {
+                ops.increment_stat(TransformationStat::RenderError);
+                errors.push(err.context("status"));
+            }
+        }
+    }
+
+    render_dynamic_metadata(
+        env,
+        &ctx,
+        &transform.dynamic_metadata,
+        parsed_body_as_json,
+        &mut ops,
+        &mut errors,
+    )?;
+
+    for key in render_removals(
+        env,
+        &ctx,
+        transform,
+        parsed_body_as_json,
+        &mut ops,
+        &mut errors,
+    )? {
+        ops.remove_response_header(&key);
+        ops.increment_stat(TransformationStat::HeaderRemoved);
+    }
+
+    for key in remove_matching_headers(transform, response_headers_map) {
         ops.remove_response_header(key);
+        ops.increment_stat(TransformationStat::HeaderRemoved);
     }
 
-    combine_errors("transform_response()", errors)
+    combine_errors("transform_response()", transform, errors)
 }
 
-pub fn create_env_with_templates(
-    config: &LocalTransformationConfig,
-) -> Result<Environment<'static>> {
-    let mut env = new_jinja_env();
-    if let Some(request) = &config.request {
-        for pair in &request.add {
-            if pair.value.is_empty() {
-                continue;
+// Renders the streaming body template for one request body chunk. The output replaces the
+// chunk, the chunk is left as is when the template fails to render.
+pub fn transform_request_chunk(
+    env: &Environment<'static>,
+    request_headers_map: &HashMap<String, String>,
+    stream_info: &StreamInfo,
+    chunk: &BodyChunk,
+) -> Result<String> {
+    render_chunk(
+        env,
+        REQUEST_BODY_TEMPLATE_LOOKUP_KEY,
+        request_headers_map,
+        None,
+        stream_info,
+        chunk,
+    )
+}
+
+// Same as transform_request_chunk() for a response body chunk
+pub fn transform_response_chunk(
+    env: &Environment<'static>,
+    request_headers_map: &HashMap<String, String>,
+    response_headers_map: &HashMap<String, String>,
+    stream_info: &StreamInfo,
+    chunk: &BodyChunk,
+) -> Result<String> {
+    render_chunk(
+        env,
+        RESPONSE_BODY_TEMPLATE_LOOKUP_KEY,
+        request_headers_map,
+        Some(response_headers_map),
+        stream_info,
+        chunk,
+    )
+}
+
+fn render_chunk(
+    env: &Environment<'static>,
+    template_key: &str,
+    request_headers_map: &HashMap<String, String>,
+    response_headers_map: Option<&HashMap<String, String>>,
+    stream_info: &StreamInfo,
+    chunk: &BodyChunk,
+) -> Result<String> {
+    let mut m = stream_context(request_headers_map, response_headers_map, stream_info);
+    m.insert(
+        CONTEXT_KEY_CHUNK.to_string(),
+        minijinja::Value::from(String::from_utf8_lossy(chunk.data)),
+    );
+    m.insert(
+        CONTEXT_KEY_CHUNK_INDEX.to_string(),
+        minijinja::Value::from(chunk.index),
+    );
+    m.insert(
+        CONTEXT_KEY_END_OF_STREAM.to_string(),
+        minijinja::Value::from(chunk.end_of_stream),
+    );
+    let template = env
+        .get_template(template_key)
+        .map(|t| t.source().to_string())
+        .unwrap_or_default();
+    render(
+        env,
+        &minijinja::Value::from(m),
+        template_key,
+        &template,
+        false,
+    )
+}
+
+// The local reply of a request transform with a directResponse, see DirectResponse
+#[derive(Debug)]
+pub struct DirectReply {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    // The reply is still sent when a template fails to render, so a request that should
+    // be stopped is never forwarded. The header is left out or the body is empty.
+    pub errors: Result<()>,
+}
+
+// Renders the directResponse of a request transform. None when the transform has none, or
+// when its methods or its condition don't match the request so it is transformed as usual.
+// A condition that fails to render is an error like for the other operations.
+pub fn render_direct_response<T: TransformationOps>(
+    env: &Environment<'static>,
+    transform: &LocalTransform,
+    request_headers_map: &HashMap<String, String>,
+    stream_info: &StreamInfo,
+    mut ops: T,
+) -> Result<Option<DirectReply>> {
+    let Some(direct_response) = &transform.direct_response else {
+        return Ok(None);
+    };
+    if !method_matches(transform, request_headers_map) {
+        return Ok(None);
+    }
+    let mut m = stream_context(request_headers_map, None, stream_info);
+    if !transform.extractors.is_empty() {
+        m.insert(
+            STATE_LOOKUP_KEY_EXTRACTIONS.to_string(),
+            minijinja::Value::from_serialize(extract(&transform.extractors, request_headers_map)),
+        );
+    }
+    let ctx = minijinja::Value::from(m);
+    if !condition_holds(env, transform, REQUEST_CONDITION_TEMPLATE_LOOKUP_KEY, &ctx)
+        .inspect_err(|_| ops.increment_stat(TransformationStat::RenderError))?
+    {
+        ops.log_debug("the condition is false, skipping the direct response");
+        return Ok(None);
+    }
+    ops.increment_stat(TransformationStat::Transformation);
+
+    let mut errors = Vec::new();
+    let mut headers = Vec::new();
+    for pair in &direct_response.headers {
+        match render_header(env, &ctx, &pair.value, pair.literal, false, &mut ops) {
+            Ok(rendered) if rendered.is_empty() => {}
+            Ok(rendered) => headers.push((pair.name.clone(), rendered)),
+            Err(err) => {
+                ops.increment_stat(TransformationStat::RenderError);
+                errors.push(err.context(format!("directResponse header {}", pair.name)));
             }
-            env.add_template_owned(pair.value.clone(), pair.value.clone())?;
         }
-        for pair in &request.set {
-            if pair.value.is_empty() {
+    }
+    let body = render(
+        env,
+        &ctx,
+        &direct_response.body,
+        &direct_response.body,
+        false,
+    )
+    .inspect_err(|_| ops.increment_stat(TransformationStat::RenderError))
+    .context("directResponse body");
+    let body = match body {
+        Ok(body) => body,
+        Err(err) => {
+            errors.push(err);
+            String::new()
+        }
+    };
+    Ok(Some(DirectReply {
+        status: direct_response.status,
+        headers,
+        body,
+        errors: combine_errors("render_direct_response()", transform, errors),
+    }))
+}
+
+// Renders the trailers of a request ending with trailers. The headers the templates see
+// are the request headers, trailer() reads the request trailers.
+pub fn transform_request_trailers<T: TransformationOps>(
+    env: &Environment<'static>,
+    transform: &LocalTransform,
+    request_headers_map: &HashMap<String, String>,
+    request_trailers_map: &HashMap<String, String>,
+    stream_info: &StreamInfo,
+    ops: T,
+) -> Result<()> {
+    transform_trailers(
+        env,
+        transform,
+        request_headers_map,
+        None,
+        request_trailers_map,
+        stream_info,
+        ops,
+    )
+}
+
+// Same as transform_request_trailers() for the response trailers
+pub fn transform_response_trailers<T: TransformationOps>(
+    env: &Environment<'static>,
+    transform: &LocalTransform,
+    request_headers_map: &HashMap<String, String>,
+    response_headers_map: &HashMap<String, String>,
+    response_trailers_map: &HashMap<String, String>,
+    stream_info: &StreamInfo,
+    ops: T,
+) -> Result<()> {
+    transform_trailers(
+        env,
+        transform,
+        request_headers_map,
+        Some(response_headers_map),
+        response_trailers_map,
+        stream_info,
+        ops,
+    )
+}
+
+fn transform_trailers<T: TransformationOps>(
+    env: &Environment<'static>,
+    transform: &LocalTransform,
+    request_headers_map: &HashMap<String, String>,
+    response_headers_map: Option<&HashMap<String, String>>,
+    trailers_map: &HashMap<String, String>,
+    stream_info: &StreamInfo,
+    mut ops: T,
+) -> Result<()> {
+    let is_response = response_headers_map.is_some();
+    if transform.trailers.is_empty() || !method_matches(transform, request_headers_map) {
+        return Ok(());
+    }
+    let mut m = stream_context(request_headers_map, response_headers_map, stream_info);
+    m.insert(
+        STATE_LOOKUP_KEY_TRAILERS.to_string(),
+        minijinja::Value::from_serialize(trailers_map),
+    );
+    let ctx = minijinja::Value::from(m);
+    let condition_key = if is_response {
+        RESPONSE_CONDITION_TEMPLATE_LOOKUP_KEY
+    } else {
+        REQUEST_CONDITION_TEMPLATE_LOOKUP_KEY
+    };
+    if !condition_holds(env, transform, condition_key, &ctx)
+        .inspect_err(|_| ops.increment_stat(TransformationStat::RenderError))?
+    {
+        ops.log_debug("the condition is false, skipping the trailers transformation");
+        return Ok(());
+    }
+
+    let mut errors = Vec::new();
+    for pair in &transform.trailers.set {
+        match render_header(env, &ctx, &pair.value, pair.literal, false, &mut ops) {
+            Ok(rendered) if rendered.is_empty() && pair.if_present => {}
+            Ok(rendered) if rendered.is_empty() => {
+                if is_response {
+                    ops.remove_response_trailer(&pair.name);
+                } else {
+                    ops.remove_request_trailer(&pair.name);
+                }
+                ops.increment_stat(TransformationStat::HeaderRemoved);
+            }
+            Ok(rendered) => {
+                if is_response {
+                    ops.set_response_trailer(&pair.name, rendered.as_bytes());
+                } else {
+                    ops.set_request_trailer(&pair.name, rendered.as_bytes());
+                }
+                ops.increment_stat(TransformationStat::HeaderSet);
+            }
+            Err(err) => {
+                ops.increment_stat(TransformationStat::RenderError);
+                errors.push(err.context(format!("trailer {}", pair.name)));
+            }
+        }
+    }
+    for name in &transform.trailers.remove {
+        if is_response {
+            ops.remove_response_trailer(name);
+        } else {
+            ops.remove_request_trailer(name);
+        }
+        ops.increment_stat(TransformationStat::HeaderRemoved);
+    }
+
+    let msg = if is_response {
+        "transform_response_trailers()"
+    } else {
+        "transform_request_trailers()"
+    };
+    combine_errors(msg, transform, errors)
+}
+
+// The context of the templates, the headers and the stream info. transform_request() and
+// transform_response() add the body and the extractions to it, the templates rendered
+// outside of them, e.g. for a body chunk or the trailers, have neither.
+fn stream_context(
+    request_headers_map: &HashMap<String, String>,
+    response_headers_map: Option<&HashMap<String, String>>,
+    stream_info: &StreamInfo,
+) -> HashMap<String, minijinja::Value> {
+    let headers_map = response_headers_map.unwrap_or(request_headers_map);
+    let mut m = HashMap::new();
+    m.insert(
+        STATE_LOOKUP_KEY_HEADERS.to_string(),
+        minijinja::Value::from_serialize(headers_map),
+    );
+    if let Some(response_headers_map) = response_headers_map {
+        m.insert(
+            STATE_LOOKUP_KEY_RESP_HEADERS.to_string(),
+            minijinja::Value::from_serialize(response_headers_map),
+        );
+    }
+    m.insert(
+        STATE_LOOKUP_KEY_REQ_HEADERS.to_string(),
+        minijinja::Value::from_serialize(request_headers_map),
+    );
+    m.insert(
+        CONTEXT_KEY_ALL_HEADERS.to_string(),
+        sorted_headers(headers_map),
+    );
+    m.insert(
+        CONTEXT_KEY_ROUTE_NAME.to_string(),
+        minijinja::Value::from_object(RouteName(stream_info.route_name.to_string())),
+    );
+    m.insert(
+        STATE_LOOKUP_KEY_SOURCE_ADDRESS.to_string(),
+        minijinja::Value::from(stream_info.source_address),
+    );
+    m.insert(
+        STATE_LOOKUP_KEY_TLS_SNI.to_string(),
+        minijinja::Value::from(stream_info.tls_sni),
+    );
+    m.insert(
+        STATE_LOOKUP_KEY_CLIENT_CERT_SUBJECT.to_string(),
+        minijinja::Value::from(stream_info.client_cert_subject),
+    );
+    if let Some(seed) = stream_info.random_seed {
+        m.insert(
+            STATE_LOOKUP_KEY_RANDOM_SEED.to_string(),
+            minijinja::Value::from(seed),
+        );
+    }
+    if let Some(filter_state) = stream_info.filter_state {
+        m.insert(
+            STATE_LOOKUP_KEY_FILTER_STATE.to_string(),
+            minijinja::Value::from_serialize(filter_state),
+        );
+    }
+    if let Some(header_counts) = stream_info.header_counts {
+        m.insert(
+            STATE_LOOKUP_KEY_HEADER_COUNTS.to_string(),
+            minijinja::Value::from_serialize(header_counts),
+        );
+    }
+    if let Some(header_values) = stream_info.header_values {
+        m.insert(
+            STATE_LOOKUP_KEY_HEADER_VALUES.to_string(),
+            minijinja::Value::from_serialize(header_values),
+        );
+    }
+    if let Some(header_bytes) = stream_info.header_bytes {
+        m.insert(
+            STATE_LOOKUP_KEY_HEADER_BYTES.to_string(),
+            minijinja::Value::from_serialize(encode_header_bytes(header_bytes)),
+        );
+    }
+    if let Some(forwarded_for) = stream_info.forwarded_for {
+        m.insert(
+            STATE_LOOKUP_KEY_FORWARDED_FOR.to_string(),
+            minijinja::Value::from_serialize(forwarded_for),
+        );
+    }
+    m
+}
+
+// The full body never exists when it is streamed, so the header templates can't read it
+// and it can't be parsed, merged or removed
+fn validate_streaming(
+    env: &Environment<'static>,
+    transform: &LocalTransform,
+    direction: &str,
+) -> Result<()> {
+    let Some(body) = transform.streaming_body_transform() else {
+        return Ok(());
+    };
+    if !matches!(body.parse_as, BodyParseBehavior::AsString) {
+        anyhow::bail!("{direction} body: streaming is only supported with parseAs AsString");
+    }
+    if body.merge.is_some() || body.remove_body {
+        anyhow::bail!("{direction} body: streaming can't be used with merge or removeBody");
+    }
+    if body.value.is_empty() {
+        anyhow::bail!("{direction} body: streaming needs a value");
+    }
+    for pair in transform.add.iter().chain(&transform.set) {
+        if !pair.literal && template_reads_body(env, &pair.value) {
+            anyhow::bail!(
+                "{direction} header {}: the body can't be referenced when it is streamed",
+                pair.name
+            );
+        }
+    }
+    for metadata in &transform.dynamic_metadata {
+        if template_reads_body(env, &metadata.value) {
+            anyhow::bail!(
+                "{direction} dynamic metadata {}/{}: the body can't be referenced when it is streamed",
+                metadata.namespace,
+                metadata.key
+            );
+        }
+    }
+    Ok(())
+}
+
+// The condition decides whether the body is read at all, so it is rendered before the body
+// is parsed and can't reference it. Otherwise a json field in the condition would fail
+// every request with a 400.
+fn validate_condition(
+    env: &Environment<'static>,
+    transform: &LocalTransform,
+    condition_key: &str,
+    direction: &str,
+) -> Result<()> {
+    if transform.condition.is_none() {
+        return Ok(());
+    }
+    // an invalid template is missing when allowInvalidTemplates is set
+    let Ok(tmpl) = env.get_template(condition_key) else {
+        return Ok(());
+    };
+    let mut body_names: Vec<String> = tmpl
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|v| {
+            BODY_VARIABLES.contains(&v.as_str()) || (!is_global(env, v) && !is_context_key(v))
+        })
+        .collect();
+    if !body_names.is_empty() {
+        body_names.sort();
+        anyhow::bail!(
+            "{direction} condition: {body_names:?} can't be used, the condition is evaluated before the body is read"
+        );
+    }
+    Ok(())
+}
+
+// In strict mode, templates are dry-run checked at config load time so a typo like
+// `headr("x-foo")` refuses the config instead of failing every request. When the body
+// is not parsed as json for that direction, the only names a template can reference are
+// the custom functions, so anything else is a typo. When it is parsed as json, the
+// undeclared names might be json fields, so only the ones that are called are caught,
+// a json field can't be. The condition is checked in every mode, see validate_condition().
+fn validate_strict_templates(
+    env: &Environment<'static>,
+    transform: &LocalTransform,
+    body_template_key: &str,
+) -> Result<()> {
+    let parses_json = transform
+        .body
+        .as_ref()
+        .is_some_and(|b| matches!(b.parse_as, BodyParseBehavior::AsJson));
+
+    let mut template_keys: Vec<&str> = transform
+        .add
+        .iter()
+        .chain(transform.set.iter())
+        .chain(transform.trailers.set.iter())
+        .chain(transform.direct_response.iter().flat_map(|d| &d.headers))
+        .filter(|pair| !pair.value.is_empty() && !pair.literal)
+        .map(|pair| pair.value.as_str())
+        .chain(transform.host_rewrite.as_deref())
+        .chain(transform.status.as_deref())
+        .chain(
+            transform
+                .direct_response
+                .iter()
+                .map(|d| d.body.as_str())
+                .filter(|body| !body.is_empty()),
+        )
+        .chain(transform.remove.iter().filter_map(HeaderRemoval::template))
+        .chain(
+            transform
+                .dynamic_metadata
+                .iter()
+                .map(|metadata| metadata.value.as_str())
+                .filter(|value| !value.is_empty()),
+        )
+        .collect();
+    if transform.body.as_ref().is_some_and(|b| !b.value.is_empty()) {
+        template_keys.push(body_template_key);
+    }
+    if let Some(patch) = transform.body.as_ref().and_then(|b| b.merge.as_ref()) {
+        merge_patch_templates(patch, &mut template_keys);
+    }
+
+    for key in template_keys {
+        // an invalid template is missing when allowInvalidTemplates is set
+        let Ok(tmpl) = env.get_template(key) else {
+            continue;
+        };
+        let mut unknown: Vec<String> = tmpl
+            .undeclared_variables(false)
+            .into_iter()
+            .filter(|v| !is_global(env, v) && !is_context_key(v))
+            .filter(|v| !parses_json || is_called(tmpl.source(), v))
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(anyhow::anyhow!(
+                "strict mode: undefined variables or functions {:?} in template {}",
+                unknown,
+                tmpl.source()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Returns true if name is called somewhere in the template source, as in `headr("x")`
+fn is_called(source: &str, name: &str) -> bool {
+    source.match_indices(name).any(|(i, _)| {
+        let before = source[..i].chars().next_back();
+        !before.is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '.')
+            && source[i + name.len()..].trim_start().starts_with('(')
+    })
+}
+
+// Compiles the templates of a transform into the env, keyed by their source except for
+// the condition and the body
+fn add_transform_templates(
+    env: &mut Environment<'static>,
+    transform: &LocalTransform,
+    direction: &str,
+    condition_key: &'static str,
+    body_key: &'static str,
+    allow_invalid: bool,
+) -> Result<()> {
+    let mut add = |what: String, key: Cow<'static, str>, source: String| match env
+        .add_template_owned(key, source)
+    {
+        Err(err) if !allow_invalid => {
+            Err(anyhow::Error::new(err).context(format!("invalid {direction} {what} template")))
+        }
+        _ => Ok(()),
+    };
+    if let Some(condition) = &transform.condition {
+        add(
+            "condition".to_string(),
+            condition_key.into(),
+            condition_template(condition),
+        )?;
+    }
+    for pair in transform.add.iter().chain(&transform.set) {
+        if pair.value.is_empty() || pair.literal {
+            continue;
+        }
+        add(
+            format!("header {}", pair.name),
+            pair.value.clone().into(),
+            pair.value.clone(),
+        )?;
+    }
+    for pair in &transform.trailers.set {
+        if pair.value.is_empty() || pair.literal {
+            continue;
+        }
+        add(
+            format!("trailer {}", pair.name),
+            pair.value.clone().into(),
+            pair.value.clone(),
+        )?;
+    }
+    if let Some(host) = &transform.host_rewrite {
+        add("hostRewrite".to_string(), host.clone().into(), host.clone())?;
+    }
+    if let Some(direct_response) = &transform.direct_response {
+        if !direct_response.body.is_empty() {
+            add(
+                "directResponse body".to_string(),
+                direct_response.body.clone().into(),
+                direct_response.body.clone(),
+            )?;
+        }
+        for pair in &direct_response.headers {
+            if pair.value.is_empty() || pair.literal {
                 continue;
             }
-            env.add_template_owned(pair.value.clone(), pair.value.clone())?;
+            add(
+                format!("directResponse header {}", pair.name),
+                pair.value.clone().into(),
+                pair.value.clone(),
+            )?;
+        }
+    }
+    for name in transform.remove.iter().filter_map(HeaderRemoval::template) {
+        add(
+            "remove".to_string(),
+            name.to_string().into(),
+            name.to_string(),
+        )?;
+    }
+    if let Some(status) = &transform.status {
+        add("status".to_string(), status.clone().into(), status.clone())?;
+    }
+    for metadata in &transform.dynamic_metadata {
+        if metadata.value.is_empty() {
+            continue;
+        }
+        add(
+            format!("dynamic metadata {}/{}", metadata.namespace, metadata.key),
+            metadata.value.clone().into(),
+            metadata.value.clone(),
+        )?;
+    }
+    if let Some(body) = &transform.body {
+        if !body.value.is_empty() {
+            add("body".to_string(), body_key.into(), body.value.clone())?;
+        }
+        if body.remove_body && (!body.value.is_empty() || body.merge.is_some()) {
+            anyhow::bail!("{direction} body: removeBody can't be used with value or merge");
         }
-        if let Some(body) = &request.body {
+        if let Some(patch) = &body.merge {
             if !body.value.is_empty() {
-                env.add_template_owned(REQUEST_BODY_TEMPLATE_LOOKUP_KEY, body.value.clone())?;
+                anyhow::bail!("{direction} body: value and merge are mutually exclusive");
+            }
+            let mut templates = Vec::new();
+            merge_patch_templates(patch, &mut templates);
+            for template in templates {
+                add(
+                    "body merge".to_string(),
+                    template.to_string().into(),
+                    template.to_string(),
+                )?;
             }
         }
     }
-    if let Some(response) = &config.response {
-        for pair in &response.add {
-            if pair.value.is_empty() {
-                continue;
+    Ok(())
+}
+
+// The secrets are the ones of the config, already loaded, see Secrets::load()
+pub fn create_env_with_templates(
+    config: &LocalTransformationConfig,
+    secrets: &Arc<Secrets>,
+) -> Result<Environment<'static>> {
+    let mut env = new_jinja_env_with(EnvOptions::from_config(config));
+    if config.strict_templates {
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+    }
+    env.set_fuel(config.max_render_fuel);
+
+    // vars are rendered once here and stored as globals, so they are visible to both the
+    // request and the response templates. They are sorted only to make the error
+    // reporting deterministic as one var cannot reference another.
+    let mut vars: Vec<_> = config.vars.iter().collect();
+    vars.sort();
+    for (name, value) in vars {
+        let rendered = env
+            .render_str(value, ())
+            .with_context(|| format!("error rendering var {}", name))?;
+        env.add_global(name.clone(), rendered);
+    }
+    // the tables are loaded once here, lookup() only reads them
+    let tables = Arc::new(config.tables.clone());
+    env.add_function(
+        "lookup",
+        move |table: &str, key: &str, default: Option<&str>| lookup(&tables, table, key, default),
+    );
+    // secret() is added after the vars are rendered, so a var can't copy a secret into a
+    // global every template sees
+    let values = secrets.clone();
+    env.add_function("secret", move |name: &str| {
+        values.get(name).map(str::to_string).ok_or_else(|| {
+            minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!("secret: no secret named {name}"),
+            )
+        })
+    });
+    if !secrets.is_empty() {
+        let secrets = secrets.clone();
+        env.add_function("context", move |state: &State| {
+            redacted_context(&secrets, context(state))
+        });
+    }
+    for (direction, transform, condition_key, body_key) in [
+        (
+            "request",
+            &config.request,
+            REQUEST_CONDITION_TEMPLATE_LOOKUP_KEY,
+            REQUEST_BODY_TEMPLATE_LOOKUP_KEY,
+        ),
+        (
+            "response",
+            &config.response,
+            RESPONSE_CONDITION_TEMPLATE_LOOKUP_KEY,
+            RESPONSE_BODY_TEMPLATE_LOOKUP_KEY,
+        ),
+    ] {
+        let Some(transform) = transform else {
+            continue;
+        };
+        let stages = transform.stages().count();
+        for (index, stage) in transform.stages().enumerate() {
+            let direction = stage_name(direction, index, stages);
+            // the condition and the body templates have a single key per direction
+            if index > 0 && (stage.condition.is_some() || stage.body.is_some()) {
+                anyhow::bail!("{direction}: only the first stage can have a condition or a body");
             }
-            env.add_template_owned(pair.value.clone(), pair.value.clone())?;
+            add_transform_templates(
+                &mut env,
+                stage,
+                &direction,
+                condition_key,
+                body_key,
+                config.allow_invalid_templates,
+            )?;
         }
-        for pair in &response.set {
-            if pair.value.is_empty() {
-                continue;
-            }
-            env.add_template_owned(pair.value.clone(), pair.value.clone())?;
+    }
+
+    if let Some(body) = config.request.as_ref().and_then(|t| t.body.as_ref()) {
+        if body.when_status.is_some() {
+            anyhow::bail!("request body: whenStatus is only supported on the response body");
         }
-        if let Some(body) = &response.body {
-            if !body.value.is_empty() {
-                env.add_template_owned(RESPONSE_BODY_TEMPLATE_LOOKUP_KEY, body.value.clone())?;
+        if body.lines {
+            anyhow::bail!("request body: lines is only supported on the response body");
+        }
+    }
+    if let Some(body) = config.response.as_ref().and_then(|t| t.body.as_ref()) {
+        if body.lines && !body.streaming {
+            anyhow::bail!("response body: lines needs streaming");
+        }
+    }
+    if config
+        .response
+        .iter()
+        .flat_map(LocalTransform::stages)
+        .any(|t| t.clear_route_cache)
+    {
+        anyhow::bail!("response: clearRouteCache is only supported on the request");
+    }
+    if config
+        .request
+        .iter()
+        .flat_map(LocalTransform::stages)
+        .any(|t| t.status.is_some())
+    {
+        anyhow::bail!("request: status is only supported on the response");
+    }
+    if config
+        .response
+        .iter()
+        .flat_map(LocalTransform::stages)
+        .any(|t| t.host_rewrite.is_some())
+    {
+        anyhow::bail!("response: hostRewrite is only supported on the request");
+    }
+    if config
+        .response
+        .iter()
+        .flat_map(LocalTransform::stages)
+        .any(|t| t.direct_response.is_some())
+    {
+        anyhow::bail!("response: directResponse is only supported on the request");
+    }
+    for transform in config.request.iter().flat_map(LocalTransform::stages) {
+        let Some(direct_response) = &transform.direct_response else {
+            continue;
+        };
+        if !(200..=599).contains(&direct_response.status) {
+            anyhow::bail!(
+                "request directResponse: status {} is not between 200 and 599",
+                direct_response.status
+            );
+        }
+        // the reply is sent before the body is read
+        if transform.body.is_some() {
+            anyhow::bail!("request: directResponse can't be used with a body transform");
+        }
+    }
+    for transform in config
+        .request
+        .iter()
+        .chain(&config.response)
+        .flat_map(LocalTransform::stages)
+    {
+        for (name, extractor) in &transform.extractors {
+            if extractor.subgroup >= extractor.regex.full_match.captures_len() {
+                anyhow::bail!(
+                    "extractor {name}: subgroup {} is out of range",
+                    extractor.subgroup
+                );
+            }
+            let replaces = extractor.mode != ExtractionMode::Extract;
+            if replaces != extractor.replacement_text.is_some() {
+                anyhow::bail!(
+                    "extractor {name}: replacementText has to be set for the replace modes only"
+                );
             }
         }
     }
+    if let Some(request) = &config.request {
+        validate_streaming(&env, request, "request")?;
+        validate_condition(
+            &env,
+            request,
+            REQUEST_CONDITION_TEMPLATE_LOOKUP_KEY,
+            "request",
+        )?;
+    }
+    if let Some(response) = &config.response {
+        validate_streaming(&env, response, "response")?;
+        validate_condition(
+            &env,
+            response,
+            RESPONSE_CONDITION_TEMPLATE_LOOKUP_KEY,
+            "response",
+        )?;
+    }
+
+    if config.strict_templates {
+        for request in config.request.iter().flat_map(LocalTransform::stages) {
+            validate_strict_templates(&env, request, REQUEST_BODY_TEMPLATE_LOOKUP_KEY)?;
+        }
+        for response in config.response.iter().flat_map(LocalTransform::stages) {
+            validate_strict_templates(&env, response, RESPONSE_BODY_TEMPLATE_LOOKUP_KEY)?;
+        }
+    }
     Ok(env)
 }
@@ -0,0 +1,615 @@
+use crate::{
+    BodyParseBehavior, BodyTransform, ConfigRegex, DynamicMetadata, ExtractionMode, Extractor,
+    HeaderRemoval, LocalTransform, LocalTransformationConfig, NameValuePair,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::BTreeMap;
+
+// The classic C++ transformation filter is configured with a TransformationTemplate, e.g.
+// `{"headers": {"x-user": {"text": "{{ header(\"x-id\") }}"}}}`, or with the request and
+// response transformations wrapping one each. Those configs, in their protojson form, are
+// converted into a LocalTransformationConfig so they can be reused as is. The fields that
+// have no equivalent are rejected rather than silently ignored.
+
+// The TransformationTemplate fields that are converted
+const TEMPLATE_FIELDS: &[&str] = &[
+    "advancedTemplates",
+    "extractors",
+    "headers",
+    "headersToAppend",
+    "headersToRemove",
+    "body",
+    "passthrough",
+    "parseBodyBehavior",
+    "ignoreErrorOnParse",
+    "dynamicMetadataValues",
+    "escapeCharacters",
+];
+const TRANSFORMATION_FIELDS: &[&str] = &["requestTransformation", "responseTransformation"];
+
+// The namespace used by the C++ filter when a dynamic metadata value doesn't have one
+const DEFAULT_METADATA_NAMESPACE: &str = "io.solo.transformation";
+
+// Returns true if the config is a TransformationTemplate or a pair of request and response
+// transformations. None of their top level fields is a LocalTransformationConfig field.
+pub fn is_legacy_config(config: &JsonValue) -> bool {
+    config.as_object().is_some_and(|fields| {
+        fields.keys().any(|key| {
+            TEMPLATE_FIELDS.contains(&key.as_str()) || TRANSFORMATION_FIELDS.contains(&key.as_str())
+        })
+    })
+}
+
+// Converts a legacy config, see is_legacy_config(). A bare TransformationTemplate is
+// applied to the request. All the fields that can't be converted are listed in the error.
+pub fn from_legacy_config(config: &JsonValue) -> Result<LocalTransformationConfig> {
+    let fields = config
+        .as_object()
+        .ok_or_else(|| anyhow!("a TransformationTemplate must be an object"))?;
+    // the config defaults, e.g. maxBufferedBodyBytes
+    let mut converted: LocalTransformationConfig =
+        serde_json::from_value(JsonValue::Object(Map::new()))?;
+    // the C++ filter lets a template set any header, a `:path` rewrite is a common one
+    converted.allow_pseudo_headers = true;
+    let mut unsupported = Vec::new();
+    if fields
+        .keys()
+        .any(|key| TRANSFORMATION_FIELDS.contains(&key.as_str()))
+    {
+        unsupported.extend(unknown_fields(fields, TRANSFORMATION_FIELDS, ""));
+        converted.request =
+            convert_transformation(fields, "requestTransformation", &mut unsupported)?;
+        converted.response =
+            convert_transformation(fields, "responseTransformation", &mut unsupported)?;
+    } else {
+        converted.request = Some(convert_template(fields, "", &mut unsupported)?);
+    }
+    if !unsupported.is_empty() {
+        return Err(UnsupportedFields(unsupported).into());
+    }
+    Ok(converted)
+}
+
+// The TransformationTemplate fields that have no equivalent, the error of
+// from_legacy_config() when there is any
+#[derive(thiserror::Error, Debug)]
+#[error("unsupported TransformationTemplate fields: {}", .0.join(", "))]
+pub struct UnsupportedFields(pub Vec<String>);
+
+fn convert_transformation(
+    fields: &Map<String, JsonValue>,
+    name: &str,
+    unsupported: &mut Vec<String>,
+) -> Result<Option<LocalTransform>> {
+    let Some(transformation) = fields.get(name) else {
+        return Ok(None);
+    };
+    let transformation = transformation
+        .as_object()
+        .ok_or_else(|| anyhow!("{name} must be an object"))?;
+    unsupported.extend(unknown_fields(
+        transformation,
+        &["transformationTemplate"],
+        &format!("{name}."),
+    ));
+    match transformation.get("transformationTemplate") {
+        Some(JsonValue::Object(template)) => convert_template(
+            template,
+            &format!("{name}.transformationTemplate."),
+            unsupported,
+        )
+        .map(Some),
+        Some(_) => bail!("{name}.transformationTemplate must be an object"),
+        None => Ok(None),
+    }
+}
+
+fn unknown_fields(fields: &Map<String, JsonValue>, known: &[&str], prefix: &str) -> Vec<String> {
+    fields
+        .keys()
+        .filter(|key| !known.contains(&key.as_str()))
+        .map(|key| format!("{prefix}{key}"))
+        .collect()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransformationTemplate {
+    #[serde(default)]
+    extractors: BTreeMap<String, Extraction>,
+    #[serde(default)]
+    headers: BTreeMap<String, InjaTemplate>,
+    #[serde(default)]
+    headers_to_append: Vec<HeaderToAppend>,
+    #[serde(default)]
+    headers_to_remove: Vec<String>,
+    #[serde(default)]
+    body: Option<InjaTemplate>,
+    #[serde(default)]
+    passthrough: Option<JsonValue>,
+    #[serde(default)]
+    parse_body_behavior: RequestBodyParse,
+    #[serde(default)]
+    ignore_error_on_parse: bool,
+    #[serde(default)]
+    dynamic_metadata_values: Vec<DynamicMetadataValue>,
+    #[serde(default)]
+    escape_characters: bool,
+}
+
+#[derive(Deserialize)]
+struct InjaTemplate {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct HeaderToAppend {
+    key: String,
+    #[serde(default)]
+    value: Option<InjaTemplate>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DynamicMetadataValue {
+    #[serde(default)]
+    metadata_namespace: String,
+    key: String,
+    #[serde(default)]
+    value: Option<InjaTemplate>,
+    #[serde(default)]
+    json_to_proto: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Extraction {
+    #[serde(default)]
+    header: Option<String>,
+    #[serde(default)]
+    body: Option<JsonValue>,
+    #[serde(default)]
+    regex: String,
+    #[serde(default)]
+    subgroup: usize,
+    #[serde(default)]
+    mode: ExtractionMode,
+    #[serde(default)]
+    replacement_text: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+enum RequestBodyParse {
+    #[default]
+    ParseAsJson,
+    DontParse,
+}
+
+// The fields that can't be converted are added to unsupported
+fn convert_template(
+    fields: &Map<String, JsonValue>,
+    prefix: &str,
+    unsupported: &mut Vec<String>,
+) -> Result<LocalTransform> {
+    unsupported.extend(unknown_fields(fields, TEMPLATE_FIELDS, prefix));
+    let template: TransformationTemplate = serde_json::from_value(JsonValue::Object(
+        fields.clone(),
+    ))
+    .with_context(|| match prefix {
+        "" => "invalid TransformationTemplate".to_string(),
+        _ => format!("invalid {}", prefix.trim_end_matches('.')),
+    })?;
+    if template.escape_characters {
+        unsupported.push(format!("{prefix}escapeCharacters"));
+    }
+
+    let mut transform = LocalTransform::default();
+    for (name, extraction) in template.extractors {
+        let field = format!("{prefix}extractors.{name}");
+        if extraction.body.is_some() {
+            unsupported.push(format!("{field}.body"));
+            continue;
+        }
+        let header = extraction
+            .header
+            .ok_or_else(|| anyhow!("{field} has neither a header nor a body"))?;
+        let regex = ConfigRegex::new(&extraction.regex)
+            .with_context(|| format!("invalid {field}.regex"))?;
+        transform.extractors.insert(
+            name,
+            Extractor {
+                header,
+                regex,
+                subgroup: extraction.subgroup,
+                mode: extraction.mode,
+                replacement_text: extraction.replacement_text,
+            },
+        );
+    }
+    transform.set = template
+        .headers
+        .into_iter()
+        .map(|(name, value)| name_value(name, Some(value)))
+        .collect();
+    transform.add = template
+        .headers_to_append
+        .into_iter()
+        .map(|pair| name_value(pair.key, pair.value))
+        .collect();
+    transform.remove = template
+        .headers_to_remove
+        .into_iter()
+        .map(HeaderRemoval::Name)
+        .collect();
+    for metadata in template.dynamic_metadata_values {
+        if metadata.json_to_proto {
+            unsupported.push(format!(
+                "{prefix}dynamicMetadataValues.{}.jsonToProto",
+                metadata.key
+            ));
+        }
+        let namespace = match metadata.metadata_namespace.as_str() {
+            "" => DEFAULT_METADATA_NAMESPACE.to_string(),
+            _ => metadata.metadata_namespace,
+        };
+        transform.dynamic_metadata.push(DynamicMetadata {
+            namespace,
+            key: metadata.key,
+            value: metadata
+                .value
+                .map(|v| translate_inja(&v.text))
+                .unwrap_or_default(),
+        });
+    }
+
+    transform.passthrough = template.passthrough.is_some();
+    let parse_as = match template.parse_body_behavior {
+        RequestBodyParse::ParseAsJson => Some(BodyParseBehavior::AsJson),
+        // the body is left alone unless it is rendered
+        RequestBodyParse::DontParse => template.body.as_ref().map(|_| BodyParseBehavior::AsString),
+    };
+    if let Some(parse_as) = parse_as.filter(|_| !transform.passthrough) {
+        transform.body = Some(BodyTransform {
+            parse_as,
+            value: template
+                .body
+                .map(|body| translate_inja(&body.text))
+                .unwrap_or_default(),
+            ignore_error_on_parse: template.ignore_error_on_parse,
+            ..Default::default()
+        });
+    }
+    Ok(transform)
+}
+
+fn name_value(name: String, value: Option<InjaTemplate>) -> NameValuePair {
+    NameValuePair {
+        name,
+        value: value.map(|v| translate_inja(&v.text)).unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+static INJA_LOOP_VARIABLE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\bloop\.(index1|index|is_first|is_last)\b").unwrap());
+// The inja functions that are filters in minijinja, called with a single argument that
+// can itself be a call, e.g. `upper(header("x"))`
+static INJA_FILTER_CALL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\b(upper|lower|length|first|last|sort|int|float)\(((?:[^()]|\([^()]*\))*)\)")
+        .unwrap()
+});
+
+// Rewrites the inja constructs that have a different spelling in minijinja. The rewrite is
+// textual, anything else is left as is and has to be valid minijinja already:
+// - the loop variables, inja's loop.index being 0 based like minijinja's loop.index0
+// - the calls to the functions that are filters, e.g. `upper(x)` becomes `(x | upper)`
+// Only the code within `{{ }}` and `{% %}` is rewritten, the text around it and the string
+// literals are left as is.
+pub fn translate_inja(template: &str) -> String {
+    let mut translated = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = find_tag_start(rest) {
+        let close = if rest[start..].starts_with("{{") {
+            "}}"
+        } else {
+            "%}"
+        };
+        // an unclosed tag is left as is, minijinja reports it
+        let Some(end) = find_tag_end(&rest[start + 2..], close).map(|end| start + 2 + end) else {
+            break;
+        };
+        translated.push_str(&rest[..start]);
+        translated.push_str(&translate_code(&rest[start..end]));
+        rest = &rest[end..];
+    }
+    translated.push_str(rest);
+    translated
+}
+
+fn find_tag_start(template: &str) -> Option<usize> {
+    match (template.find("{{"), template.find("{%")) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// The end of the tag, after its closing delimiter. A delimiter within a string literal
+// doesn't close the tag.
+fn find_tag_end(code: &str, close: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in code.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if code[i..].starts_with(close) => return Some(i + close.len()),
+            None => {}
+        }
+    }
+    None
+}
+
+fn translate_code(code: &str) -> String {
+    let code = rewrite_code(code, &INJA_LOOP_VARIABLE, |caps, _| {
+        match &caps[1] {
+            "index1" => "loop.index",
+            "index" => "loop.index0",
+            "is_first" => "loop.first",
+            _ => "loop.last",
+        }
+        .to_string()
+    });
+    rewrite_code(&code, &INJA_FILTER_CALL, |caps, code| {
+        format!("({} | {})", &code[caps.get(2).unwrap().range()], &caps[1])
+    })
+}
+
+// Replaces the matches of re in the code outside of the string literals. The regex runs
+// over a copy of the code with the literals blanked out, the replacement reads the original
+// code at the same offsets.
+fn rewrite_code(code: &str, re: &Regex, replacement: impl Fn(&Captures, &str) -> String) -> String {
+    let masked = mask_string_literals(code);
+    let mut rewritten = String::with_capacity(code.len());
+    let mut last = 0;
+    for caps in re.captures_iter(&masked) {
+        let m = caps.get(0).unwrap();
+        rewritten.push_str(&code[last..m.start()]);
+        rewritten.push_str(&replacement(&caps, code));
+        last = m.end();
+    }
+    rewritten.push_str(&code[last..]);
+    rewritten
+}
+
+// The code with the content of its string literals replaced with spaces, the same number
+// of bytes so the offsets don't change
+fn mask_string_literals(code: &str) -> String {
+    let mut masked = String::with_capacity(code.len());
+    let mut quote = None;
+    let mut escaped = false;
+    for c in code.chars() {
+        let in_literal = match quote {
+            Some(_) if escaped => {
+                escaped = false;
+                true
+            }
+            Some(_) if c == '\\' => {
+                escaped = true;
+                true
+            }
+            Some(q) if c == q => {
+                quote = None;
+                false
+            }
+            Some(_) => true,
+            None => {
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+                false
+            }
+        };
+        if in_literal {
+            masked.extend(std::iter::repeat_n(' ', c.len_utf8()));
+        } else {
+            masked.push(c);
+        }
+    }
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn convert(legacy: JsonValue) -> LocalTransformationConfig {
+        assert!(is_legacy_config(&legacy), "not a legacy config: {legacy}");
+        from_legacy_config(&legacy).unwrap()
+    }
+
+    #[test]
+    fn test_from_legacy_config() {
+        let cases = [
+            (
+                "headers",
+                json!({
+                    "headers": {
+                        "x-user": { "text": "{{ header(\"x-id\") }}" },
+                        ":path": { "text": "/v2{{ header(\":path\") }}" }
+                    },
+                    "headersToAppend": [{ "key": "x-tag", "value": { "text": "a" } }],
+                    "headersToRemove": ["x-internal"],
+                    "parseBodyBehavior": "DontParse"
+                }),
+                json!({
+                    "request": {
+                        "set": [
+                            { "name": ":path", "value": "/v2{{ header(\":path\") }}" },
+                            { "name": "x-user", "value": "{{ header(\"x-id\") }}" }
+                        ],
+                        "add": [{ "name": "x-tag", "value": "a" }],
+                        "remove": ["x-internal"]
+                    }
+                }),
+            ),
+            (
+                "json body",
+                json!({
+                    "extractors": {
+                        "id": { "header": ":path", "regex": "/users/(\\d+)", "subgroup": 1 },
+                        "path": {
+                            "header": ":path",
+                            "regex": "/v1",
+                            "mode": "SINGLE_REPLACE",
+                            "replacementText": "/v2"
+                        }
+                    },
+                    "body": { "text": "{\"id\": \"{{ extraction(\"id\") }}\", \"n\": {{ length(items) }}}" },
+                    "ignoreErrorOnParse": true,
+                    "advancedTemplates": true
+                }),
+                json!({
+                    "request": {
+                        "extractors": {
+                            "id": { "header": ":path", "regex": "/users/(\\d+)", "subgroup": 1 },
+                            "path": {
+                                "header": ":path",
+                                "regex": "/v1",
+                                "mode": "SINGLE_REPLACE",
+                                "replacementText": "/v2"
+                            }
+                        },
+                        "body": {
+                            "parseAs": "AsJson",
+                            "value": "{\"id\": \"{{ extraction(\"id\") }}\", \"n\": {{ (items | length) }}}",
+                            "ignoreErrorOnParse": true
+                        }
+                    }
+                }),
+            ),
+            (
+                "passthrough and metadata",
+                json!({
+                    "passthrough": {},
+                    "dynamicMetadataValues": [
+                        { "key": "user", "value": { "text": "{{ upper(header(\"x-user\")) }}" } },
+                        { "metadataNamespace": "audit", "key": "tenant", "value": { "text": "t1" } }
+                    ]
+                }),
+                json!({
+                    "request": {
+                        "passthrough": true,
+                        "dynamicMetadata": [
+                            {
+                                "namespace": "io.solo.transformation",
+                                "key": "user",
+                                "value": "{{ (header(\"x-user\") | upper) }}"
+                            },
+                            { "namespace": "audit", "key": "tenant", "value": "t1" }
+                        ]
+                    }
+                }),
+            ),
+            (
+                "request and response",
+                json!({
+                    "requestTransformation": {
+                        "transformationTemplate": {
+                            "headers": { "x-a": { "text": "1" } },
+                            "parseBodyBehavior": "DontParse"
+                        }
+                    },
+                    "responseTransformation": {
+                        "transformationTemplate": {
+                            "body": { "text": "{% for i in items %}{{ loop.index }}{% endfor %}" }
+                        }
+                    }
+                }),
+                json!({
+                    "request": { "set": [{ "name": "x-a", "value": "1" }] },
+                    "response": {
+                        "body": {
+                            "parseAs": "AsJson",
+                            "value": "{% for i in items %}{{ loop.index0 }}{% endfor %}"
+                        }
+                    }
+                }),
+            ),
+        ];
+        for (name, legacy, mut expected) in cases {
+            // the pseudo headers can always be set, see from_legacy_config()
+            expected["allowPseudoHeaders"] = true.into();
+            let expected: LocalTransformationConfig = serde_json::from_value(expected).unwrap();
+            assert_eq!(convert(legacy), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_from_legacy_config_unsupported_fields() {
+        let legacy = json!({
+            "headers": { "x-a": { "text": "1" } },
+            "mergeExtractorsToBody": {},
+            "escapeCharacters": true,
+            "extractors": { "b": { "body": {}, "regex": ".*" } },
+            "dynamicMetadataValues": [{ "key": "k", "jsonToProto": true }]
+        });
+        let err = from_legacy_config(&legacy).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "unsupported TransformationTemplate fields: mergeExtractorsToBody, \
+             escapeCharacters, extractors.b.body, dynamicMetadataValues.k.jsonToProto"
+        );
+
+        let legacy = json!({
+            "requestTransformation": { "headerBodyTransform": {} },
+            "responseTransformation": { "transformationTemplate": { "mergeJsonKeys": {} } }
+        });
+        let err = from_legacy_config(&legacy).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "unsupported TransformationTemplate fields: requestTransformation.headerBodyTransform, \
+             responseTransformation.transformationTemplate.mergeJsonKeys"
+        );
+
+        assert!(!is_legacy_config(
+            &json!({ "request": { "remove": ["x"] } })
+        ));
+    }
+
+    #[test]
+    fn test_translate_inja() {
+        assert_eq!(
+            translate_inja("{{ loop.index }}/{{ loop.index1 }}{% if loop.is_last %}.{% endif %}"),
+            "{{ loop.index0 }}/{{ loop.index }}{% if loop.last %}.{% endif %}"
+        );
+        assert_eq!(
+            translate_inja("{{ upper(header(\"x\")) }} {{ int(a) + length(b) }} {{ to_int(c) }}"),
+            "{{ (header(\"x\") | upper) }} {{ (a | int) + (b | length) }} {{ to_int(c) }}"
+        );
+        // the text around the tags is left as is
+        assert_eq!(
+            translate_inja("Log in first(please), loop.index: {{ header(\"x\") }}"),
+            "Log in first(please), loop.index: {{ header(\"x\") }}"
+        );
+        // and so are the string literals, a closing delimiter within them included
+        assert_eq!(
+            translate_inja("{{ header(\"sort(a)\") }} {{ upper('}} loop.index') }}"),
+            "{{ header(\"sort(a)\") }} {{ ('}} loop.index' | upper) }}"
+        );
+        assert_eq!(
+            translate_inja("{% if length(header(\"x\")) > 0 %}{{ \"it's\" }}{% endif %}"),
+            "{% if (header(\"x\") | length) > 0 %}{{ \"it's\" }}{% endif %}"
+        );
+        // an unclosed tag is left as is
+        assert_eq!(translate_inja("{{ upper(a)"), "{{ upper(a)");
+    }
+}
@@ -6,29 +6,633 @@ will make the linter unhappy. :w
 */
 
 use anyhow::Result;
-use serde::Deserialize;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
 use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
 
+mod form;
+pub mod gzip;
 pub mod jinja;
+pub mod legacy;
+pub mod schema;
+pub mod secrets;
 
-#[derive(Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct LocalTransformationConfig {
-    #[serde(default)]
+    // Either a transform or a list of transforms, the stages, applied in order. Each stage
+    // sees the headers as the previous one left them, e.g. a first stage strips the internal
+    // headers and a second one adds headers computed without them, see
+    // LocalTransform::stages().
+    #[serde(default, deserialize_with = "deserialize_stages")]
     pub request: Option<LocalTransform>,
+    #[serde(default, deserialize_with = "deserialize_stages")]
+    pub response: Option<LocalTransform>,
+    // When set, undefined variables and unknown functions are render errors instead of
+    // silently rendering as empty strings. A header whose template fails to render is
+    // then left untouched rather than removed.
+    #[serde(default, rename = "strictTemplates")]
+    pub strict_templates: bool,
+    // The fields that are not known are ignored by default, so an older filter accepts a
+    // config from a newer control plane. When set, they refuse the config instead, e.g. a
+    // typo like `requset`, see schema::unknown_fields().
+    #[serde(default, rename = "strictSchema")]
+    pub strict_schema: bool,
+    // A template that doesn't compile, e.g. `{{ header("x") }`, rejects the config. When set,
+    // the config is accepted anyway and such a template fails to render on each request
+    // instead, so its header is removed.
+    #[serde(default, rename = "allowInvalidTemplates")]
+    pub allow_invalid_templates: bool,
+    // Shared variables that can be referenced by name from any template, e.g. `{{ region }}`.
+    // The values can be templates themselves but they are rendered only once when the
+    // config is loaded, so they can use env() but not the request/response accessors.
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    // Static tables the templates read with lookup(), e.g. `{"region_by_cc": {"FR": "eu-west"}}`
+    // for `{{ lookup("region_by_cc", header("cf-ipcountry"), "us-east") }}`
+    #[serde(default)]
+    pub tables: HashMap<String, HashMap<String, String>>,
+    // Values read from files when the config is loaded, e.g. API keys that can't be inline
+    // in the config: `{"api_key": {"file": "/etc/secrets/api-key"}}`. The templates read
+    // them with `{{ secret("api_key") }}` and they are redacted from the logs, the errors
+    // and the context() dump, see secrets::Secrets.
+    #[serde(default)]
+    pub secrets: HashMap<String, SecretSource>,
+    // The header values that are not valid UTF-8 are decoded lossily, the invalid sequences
+    // are replaced with U+FFFD and header_bytes_base64() has the exact bytes. By default, the
+    // headers with a name that is not valid UTF-8 are left out of the template context.
+    // When set, their names are decoded lossily too so they are at least visible.
+    #[serde(default, rename = "lossyHeaderDecoding")]
+    pub lossy_header_decoding: bool,
+    // Which value header(), request_header() and response_header() return for a header
+    // received more than once, the last one by default. header_values() returns all of
+    // them either way. The cookies are always joined in a single value.
+    #[serde(default, rename = "duplicateHeaders")]
+    pub duplicate_headers: DuplicateHeaderPolicy,
+    // When set, requests carrying this header are passed through without any request or
+    // response transformation, e.g. to bypass them while debugging. Anyone able to send the
+    // header can turn the transformations off, so only use it on internal listeners.
+    #[serde(default, rename = "disableOnHeader")]
+    pub disable_on_header: Option<String>,
+    // The maximum number of request body bytes buffered for a body transformation.
+    // A request with a bigger body is rejected with a 413.
+    #[serde(
+        default = "default_max_buffered_body_bytes",
+        rename = "maxBufferedBodyBytes"
+    )]
+    pub max_buffered_body_bytes: usize,
+    // The number of template instructions a single render can run, e.g. to stop a template
+    // with deeply nested loops from stalling the worker thread. A render going over it
+    // fails like for any other rendering error. Unlimited when unset.
+    #[serde(default, rename = "maxRenderFuel")]
+    pub max_render_fuel: Option<u64>,
+    // How the values printed by the templates are escaped, see AutoEscapeMode
+    #[serde(default, rename = "autoEscape")]
+    pub auto_escape: AutoEscapeMode,
+    // What is done when a transformation fails, e.g. when a header fails to render
+    #[serde(default, rename = "onError")]
+    pub on_error: OnError,
+    // Prepended to the names of the counters and the histogram, e.g. to tell apart the
+    // stats of two filters in the same listener
+    #[serde(default = "default_stat_prefix", rename = "statPrefix")]
+    pub stat_prefix: String,
+    // The headers the set, add and remove operations can't change, e.g. `authorization`.
+    // Defaults to the hop-by-hop headers, plus content-length unless the transform rewrites
+    // the body. The operations targeting them are skipped with a warning, see
+    // skip_protected_headers().
+    #[serde(default, rename = "protectedHeaders")]
+    pub protected_headers: Option<Vec<String>>,
+    // The pseudo headers like `:path` are always protected unless this is set, e.g. to
+    // rewrite the path or the method
+    #[serde(default, rename = "allowPseudoHeaders")]
+    pub allow_pseudo_headers: bool,
+    // When set, replace_with_random() is seeded from the value of this request header,
+    // e.g. `x-request-id`, so its output is the same for a given header value and for the
+    // same string to replace within a request. Without the header it stays random.
+    #[serde(default, rename = "randomSeedHeader")]
+    pub random_seed_header: Option<String>,
+    // The template functions taken out of the environment, e.g. `env` in multi-tenant
+    // setups so the config authors can't read the process environment. A template calling
+    // one of them fails like for any unknown name, strictTemplates refuses the config.
+    #[serde(default, rename = "disabledFunctions")]
+    pub disabled_functions: Vec<FunctionCategory>,
+    // Request and response transforms applied only to the requests they match, e.g. to
+    // transform `/api` and `/static` differently on the same route. The matches are
+    // evaluated in order and the first one wins. When none matches, the top level
+    // request and response transforms are applied.
+    #[serde(default)]
+    pub transforms: Vec<ConditionalTransform>,
+}
+
+// The settings a route config sets explicitly. Under the merge policy, the ones the route
+// leaves unset are taken from the filter config rather than from the defaults, see
+// LocalTransformationConfig::merge().
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct RouteSettings {
+    #[serde(default, rename = "strictTemplates")]
+    pub strict_templates: Option<bool>,
+    #[serde(default, rename = "allowInvalidTemplates")]
+    pub allow_invalid_templates: Option<bool>,
+    #[serde(default, rename = "lossyHeaderDecoding")]
+    pub lossy_header_decoding: Option<bool>,
+    #[serde(default, rename = "duplicateHeaders")]
+    pub duplicate_headers: Option<DuplicateHeaderPolicy>,
+    #[serde(default, rename = "maxBufferedBodyBytes")]
+    pub max_buffered_body_bytes: Option<usize>,
+    #[serde(default, rename = "autoEscape")]
+    pub auto_escape: Option<AutoEscapeMode>,
+    #[serde(default, rename = "allowPseudoHeaders")]
+    pub allow_pseudo_headers: Option<bool>,
+    #[serde(default, rename = "disabledFunctions")]
+    pub disabled_functions: Option<Vec<FunctionCategory>>,
+}
+
+fn default_max_buffered_body_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_stat_prefix() -> String {
+    "transformation_".to_string()
+}
+
+// The headers protected when protectedHeaders is not set, besides content-length
+const DEFAULT_PROTECTED_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+impl LocalTransformationConfig {
+    // Returns the config for a route merging this filter level config with the route
+    // config. The request and response transforms are merged with LocalTransform::merge()
+    // and the route vars, tables and secrets override the filter level ones with the same
+    // name. The settings like strictTemplates or maxBufferedBodyBytes are the route ones
+    // when it sets them, see settings, and the filter level ones otherwise. The conditional
+    // transforms are the route ones like when the route config replaces the filter config.
+    pub fn merge(
+        &self,
+        route: &LocalTransformationConfig,
+        settings: &RouteSettings,
+    ) -> LocalTransformationConfig {
+        let mut vars = self.vars.clone();
+        vars.extend(route.vars.clone());
+        let mut tables = self.tables.clone();
+        tables.extend(route.tables.clone());
+        let mut secrets = self.secrets.clone();
+        secrets.extend(route.secrets.clone());
+        LocalTransformationConfig {
+            request: merge_transform(&self.request, &route.request),
+            response: merge_transform(&self.response, &route.response),
+            vars,
+            tables,
+            secrets,
+            disable_on_header: route
+                .disable_on_header
+                .clone()
+                .or_else(|| self.disable_on_header.clone()),
+            strict_templates: settings.strict_templates.unwrap_or(self.strict_templates),
+            allow_invalid_templates: settings
+                .allow_invalid_templates
+                .unwrap_or(self.allow_invalid_templates),
+            lossy_header_decoding: settings
+                .lossy_header_decoding
+                .unwrap_or(self.lossy_header_decoding),
+            duplicate_headers: settings.duplicate_headers.unwrap_or(self.duplicate_headers),
+            max_buffered_body_bytes: settings
+                .max_buffered_body_bytes
+                .unwrap_or(self.max_buffered_body_bytes),
+            max_render_fuel: route.max_render_fuel.or(self.max_render_fuel),
+            auto_escape: settings.auto_escape.unwrap_or(self.auto_escape),
+            protected_headers: route
+                .protected_headers
+                .clone()
+                .or_else(|| self.protected_headers.clone()),
+            allow_pseudo_headers: settings
+                .allow_pseudo_headers
+                .unwrap_or(self.allow_pseudo_headers),
+            random_seed_header: route
+                .random_seed_header
+                .clone()
+                .or_else(|| self.random_seed_header.clone()),
+            disabled_functions: settings
+                .disabled_functions
+                .clone()
+                .unwrap_or_else(|| self.disabled_functions.clone()),
+            ..route.clone()
+        }
+    }
+
+    // Drops the set, add and remove operations of the request and response transforms
+    // targeting a protected header, see protectedHeaders. Returns a warning naming each
+    // operation dropped.
+    pub fn skip_protected_headers(&mut self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let protected_headers = &self.protected_headers;
+        let allow_pseudo_headers = self.allow_pseudo_headers;
+        for (direction, transform) in [
+            ("request", &mut self.request),
+            ("response", &mut self.response),
+        ] {
+            let Some(transform) = transform else {
+                continue;
+            };
+            let stages = transform.stages().count();
+            transform.for_each_stage_mut(|index, transform| {
+                let direction = stage_name(direction, index, stages);
+                // the host rewrite is an explicit opt-in, only an explicit protectedHeaders
+                // entry for the host skips it
+                if transform.host_rewrite.is_some()
+                    && protected_headers.as_ref().is_some_and(|protected| {
+                        protected.iter().any(|p| {
+                            p.eq_ignore_ascii_case("host") || p.eq_ignore_ascii_case(":authority")
+                        })
+                    })
+                {
+                    transform.host_rewrite = None;
+                    warnings.push(format!(
+                        "skipping {direction}.hostRewrite, the host is a protected header"
+                    ));
+                }
+                let rewrites_body = transform.body_transform().is_some_and(|body| {
+                    !body.value.is_empty() || body.merge.is_some() || body.remove_body
+                });
+                let is_protected = |name: &str| {
+                    let name = name.to_lowercase();
+                    if name.starts_with(':') {
+                        return !allow_pseudo_headers;
+                    }
+                    match protected_headers {
+                        Some(protected) => protected.iter().any(|p| p.eq_ignore_ascii_case(&name)),
+                        None => {
+                            DEFAULT_PROTECTED_HEADERS.contains(&name.as_str())
+                                || (name == "content-length" && !rewrites_body)
+                        }
+                    }
+                };
+                let mut skip = |operation: &str, index: usize, name: &str| {
+                    if !is_protected(name) {
+                        return false;
+                    }
+                    warnings.push(format!(
+                        "skipping {direction}.{operation}[{index}], {name} is a protected header"
+                    ));
+                    true
+                };
+                for (operation, pairs) in [("set", &mut transform.set), ("add", &mut transform.add)]
+                {
+                    let mut index = 0;
+                    pairs.retain(|pair| {
+                        index += 1;
+                        !skip(operation, index - 1, &pair.name)
+                    });
+                }
+                let mut index = 0;
+                transform.remove.retain(|removal| {
+                    index += 1;
+                    // the patterns never match the pseudo headers
+                    removal
+                        .name()
+                        .is_none_or(|name| !skip("remove", index - 1, name))
+                });
+            });
+        }
+        warnings
+    }
+
+    // Marks the set and add values of the request and response transforms that have no
+    // template syntax as literal, they are written as is without going through jinja.
+    pub fn mark_literal_values(&mut self) {
+        for transform in [&mut self.request, &mut self.response]
+            .into_iter()
+            .flatten()
+        {
+            transform.for_each_stage_mut(|_, transform| {
+                for pair in transform
+                    .set
+                    .iter_mut()
+                    .chain(transform.add.iter_mut())
+                    .chain(transform.trailers.set.iter_mut())
+                    .chain(
+                        transform
+                            .direct_response
+                            .iter_mut()
+                            .flat_map(|d| d.headers.iter_mut()),
+                    )
+                {
+                    pair.literal |= !pair.has_template_syntax();
+                }
+            });
+        }
+    }
+}
+
+// Names a stage in the errors and warnings, e.g. `request[1]`. A direction with a single
+// transform is named as is.
+pub fn stage_name(direction: &str, index: usize, stages: usize) -> String {
+    if stages > 1 {
+        format!("{direction}[{index}]")
+    } else {
+        direction.to_string()
+    }
+}
+
+// Deserializes a direction given either as a transform or as a list of stages. The
+// stages after the first one are kept in its later_stages, an empty list is no transform.
+fn deserialize_stages<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<LocalTransform>, D::Error> {
+    struct StagesVisitor;
+
+    impl<'de> Visitor<'de> for StagesVisitor {
+        type Value = Option<LocalTransform>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a transform or a list of transforms")
+        }
+
+        fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            // deserialized as a struct so schema::unknown_fields() checks its fields
+            deserializer.deserialize_struct(
+                "LocalTransform",
+                schema::struct_fields::<LocalTransform>(),
+                self,
+            )
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            LocalTransform::deserialize(MapAccessDeserializer::new(map)).map(Some)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut stages = Vec::new();
+            while let Some(stage) = seq.next_element::<LocalTransform>()? {
+                stages.push(stage);
+            }
+            let mut stages = stages.into_iter();
+            Ok(stages.next().map(|mut first| {
+                first.later_stages = stages.collect();
+                first
+            }))
+        }
+    }
+
+    deserializer.deserialize_option(StagesVisitor)
+}
+
+fn merge_transform(
+    filter: &Option<LocalTransform>,
+    route: &Option<LocalTransform>,
+) -> Option<LocalTransform> {
+    match (filter, route) {
+        (Some(filter), Some(route)) => Some(filter.merge(route)),
+        (filter, None) => filter.clone(),
+        (None, route) => route.clone(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SecretSource {
+    // The file the secret is read from, a trailing newline is dropped
+    pub file: String,
+}
+
+// The groups of template functions that can be disabled, see disabledFunctions
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FunctionCategory {
+    // env(), reading the process environment
+    Env,
+    // replace_with_random()
+    Random,
+    // crc32() and pseudonymize()
+    Hashing,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoEscapeMode {
+    // The values are printed as is
+    #[default]
+    None,
+    // The values are printed as json, so a string is quoted and escaped. The quotes are
+    // part of the output, so the templates use `{"name": {{ name }}}` without quotes.
+    Json,
+    // The html special characters `<>&"'/` are escaped as entities
+    Html,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    // The error is logged and the transformation goes on, a header that failed to render
+    // is removed
+    #[default]
+    Continue,
+    // Fails closed, e.g. for the headers an authorization depends on. A request is rejected
+    // with a local reply instead of being sent upstream. A response gets the status but
+    // its body is left as is, it may already be on its way.
+    Reject(RejectReply),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RejectReply {
+    #[serde(default = "default_reject_status")]
+    pub status: u32,
+    // The body of the local reply. It is a template rendered once when the config is
+    // loaded, like the vars.
     #[serde(default)]
+    pub body: String,
+}
+
+fn default_reject_status() -> u32 {
+    500
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ConditionalTransform {
+    #[serde(rename = "match")]
+    pub matcher: RequestMatch,
+    #[serde(default, deserialize_with = "deserialize_stages")]
+    pub request: Option<LocalTransform>,
+    #[serde(default, deserialize_with = "deserialize_stages")]
     pub response: Option<LocalTransform>,
 }
 
-#[derive(Default, Clone, Deserialize)]
+// Matches a request on its headers. All the conditions that are set have to match, so an
+// empty match matches all the requests.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct RequestMatch {
+    // Matched against `:path`, including the query string
+    #[serde(default, rename = "pathPrefix")]
+    pub path_prefix: Option<String>,
+    // Has to match the whole `:path`, without the query string
+    #[serde(default, rename = "pathRegex")]
+    pub path_regex: Option<ConfigRegex>,
+    // Matched against `:method`, e.g. `POST`
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<HeaderMatch>,
+}
+
+// Matches a header by name. The header only has to be present when neither exact nor
+// regex is set.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct HeaderMatch {
+    pub name: String,
+    #[serde(default)]
+    pub exact: Option<String>,
+    // Has to match the whole value
+    #[serde(default)]
+    pub regex: Option<ConfigRegex>,
+}
+
+impl RequestMatch {
+    // The headers map has lowercase names, like the one built by the filter
+    pub fn matches(&self, headers: &HashMap<String, String>) -> bool {
+        let path = headers.get(":path").map(String::as_str).unwrap_or_default();
+        if self
+            .path_prefix
+            .as_ref()
+            .is_some_and(|prefix| !path.starts_with(prefix.as_str()))
+        {
+            return false;
+        }
+        let path_without_query = path.split('?').next().unwrap_or_default();
+        if self
+            .path_regex
+            .as_ref()
+            .is_some_and(|regex| !regex.full_match.is_match(path_without_query))
+        {
+            return false;
+        }
+        if self
+            .method
+            .as_ref()
+            .is_some_and(|method| headers.get(":method") != Some(method))
+        {
+            return false;
+        }
+        self.headers.iter().all(|header| {
+            let Some(value) = headers.get(&header.name.to_ascii_lowercase()) else {
+                return false;
+            };
+            header.exact.as_ref().is_none_or(|exact| exact == value)
+                && header
+                    .regex
+                    .as_ref()
+                    .is_none_or(|regex| regex.full_match.is_match(value))
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
 pub struct LocalTransform {
+    // Names the transform in the errors and logs, e.g. when a template fails to render
+    #[serde(default)]
+    pub name: Option<String>,
     #[serde(default)]
     pub add: Vec<NameValuePair>,
+    // The set operations are applied in order, so when several of them target the same
+    // header the last one wins. Use add to keep all the values instead.
     #[serde(default)]
     pub set: Vec<NameValuePair>,
+    // By default, all the set templates see the headers as received. When set, each set
+    // template sees the headers as changed by the previous ones, e.g. `{{ header("x-a") }}`
+    // renders the value set just before for x-a. The extractions are not updated.
+    #[serde(default, rename = "sequentialSet")]
+    pub sequential_set: bool,
+    // The headers to remove by name, prefix, glob or regex, see HeaderRemoval. A name can be
+    // a template, a name rendered empty removes nothing.
     #[serde(default)]
-    pub remove: Vec<String>,
+    pub remove: Vec<HeaderRemoval>,
+    // Only for the request, a template rendering the new host, e.g. to map a vanity domain
+    // to an internal one. :authority is set, and Host too when the request has one. An
+    // empty value leaves the host untouched. Combine with clearRouteCache so the request
+    // is routed for the new host.
+    #[serde(default, rename = "hostRewrite")]
+    pub host_rewrite: Option<String>,
+    // Only for the response, a template rendering the new :status, e.g.
+    // `{% if response_code() == "502" %}503{% endif %}`. A value that is not an integer
+    // between 100 and 599 leaves the status untouched, an empty one silently.
+    #[serde(default)]
+    pub status: Option<String>,
+    // Only for the request, replies to the downstream instead of forwarding the request,
+    // e.g. with a 401 when the API key is missing or a 302 to a login page. Guard it with a
+    // condition, methods or a conditional transform so only some requests get it. The
+    // other operations of the transform are not applied then.
+    #[serde(default, rename = "directResponse")]
+    pub direct_response: Option<DirectResponse>,
+    // Copies the headers matching a prefix under a new prefix, e.g. to keep the original
+    // values as `x-orig-*`. The copies are set before the set and add operations.
+    #[serde(default, rename = "copyPrefix")]
+    pub copy_prefix: Vec<CopyPrefix>,
     #[serde(default)]
     pub body: Option<BodyTransform>,
+    // When set, the body is never buffered and is passed through as is. The body
+    // transform, if any, is ignored and the headers are transformed without the body.
+    #[serde(default)]
+    pub passthrough: bool,
+    // Body fields copied into the dynamic metadata, e.g. for the rate limiter to key on them.
+    // Only applies when the body is parsed as json.
+    #[serde(default, rename = "metadataFromBody")]
+    pub metadata_from_body: Vec<MetadataFromBody>,
+    // Values extracted from the headers with a regex, available to the templates by name
+    // as `{{ extraction("user_id") }}`. A request extractor reads the request headers and a
+    // response extractor the response headers.
+    #[serde(default)]
+    pub extractors: HashMap<String, Extractor>,
+    // Templates rendered into the dynamic metadata, e.g. for the access logs or the rate
+    // limiter. They are rendered with the same context as the headers, a value rendered
+    // empty is not written.
+    #[serde(default, rename = "dynamicMetadata")]
+    pub dynamic_metadata: Vec<DynamicMetadata>,
+    // An expression, e.g. `header("x-debug") == "1"`. When set, the transform is applied only
+    // if it is true, otherwise the headers and the body are left as is. It can use the
+    // headers and the extractions but not the body. A condition that fails to render skips
+    // the transform. A streaming body transform is not affected by the condition.
+    #[serde(default)]
+    pub condition: Option<String>,
+    // The request methods the transform applies to, e.g. `["POST", "PUT"]`, compared to the
+    // `:method` header ignoring the case. The transform is skipped for any other method,
+    // like for a false condition. Empty applies it to every method.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    // When set on the request transform, the route is picked again once the request headers
+    // have been changed, e.g. so a rewritten `:path` or host goes to the matching route. The
+    // Content-Length and Content-Type updates of a body transform don't count as a change.
+    #[serde(default, rename = "clearRouteCache")]
+    pub clear_route_cache: bool,
+    // The maximum size in bytes of a rendered set or add value, e.g. so a template echoing
+    // the body doesn't make the upstream reply with a 431. Unlimited when unset.
+    #[serde(default, rename = "maxHeaderValueBytes")]
+    pub max_header_value_bytes: Option<usize>,
+    #[serde(default, rename = "oversizedHeaderValue")]
+    pub oversized_header_value: OversizedValuePolicy,
+    // The trailers to set or remove, e.g. a gRPC response grpc-status-details-bin
+    #[serde(default)]
+    pub trailers: TrailersTransform,
+    // The stages applied after this one when the direction is a list of transforms. They
+    // can't have a body or a condition, the body is transformed once by the first stage.
+    #[serde(skip)]
+    pub later_stages: Vec<LocalTransform>,
 }
 
 impl LocalTransform {
@@ -36,19 +640,255 @@ impl LocalTransform {
         self.add.is_empty()
             && self.set.is_empty()
             && self.remove.is_empty()
+            && self.host_rewrite.is_none()
+            && self.status.is_none()
+            && self.copy_prefix.is_empty()
+            && self.dynamic_metadata.is_empty()
+            && self
+                .extractors
+                .values()
+                .all(|e| e.mode == ExtractionMode::Extract)
             && self.body.as_ref().map(|c| c.is_empty()).unwrap_or(true)
+            && self.trailers.is_empty()
+            && self.direct_response.is_none()
+            && self.later_stages.iter().all(LocalTransform::is_empty)
+    }
+
+    // Returns this transform followed by its later stages, in the order they are applied
+    pub fn stages(&self) -> impl Iterator<Item = &LocalTransform> {
+        std::iter::once(self).chain(&self.later_stages)
+    }
+
+    // Calls f with the index and each stage of this transform, see stages()
+    pub fn for_each_stage_mut(&mut self, mut f: impl FnMut(usize, &mut LocalTransform)) {
+        f(0, self);
+        for (index, stage) in self.later_stages.iter_mut().enumerate() {
+            f(index + 1, stage);
+        }
+    }
+
+    // Returns true if the transform has to wait for the full body before it can be applied,
+    // either to render a new body or to parse the body for the header templates.
+    // Otherwise, the headers can be transformed right away and the body passed through.
+    pub fn needs_body(&self) -> bool {
+        self.body_transform()
+            .is_some_and(|c| !c.is_empty() && !c.streaming)
+    }
+
+    // Returns the body transform to apply to each chunk, None if the body is not streamed
+    pub fn streaming_body_transform(&self) -> Option<&BodyTransform> {
+        self.body_transform().filter(|c| c.streaming)
+    }
+
+    // Returns this filter level transform merged with a route transform. The route header
+    // operations are applied after the filter level ones, so a route set wins over a
+    // filter level set of the same header. The route body transform and extractors
+    // override the filter level ones, and the body is passed through if either says so.
+    // The filter level later stages are applied before the route ones.
+    pub fn merge(&self, route: &LocalTransform) -> LocalTransform {
+        let mut extractors = self.extractors.clone();
+        extractors.extend(route.extractors.clone());
+        LocalTransform {
+            name: route.name.clone().or_else(|| self.name.clone()),
+            add: [self.add.as_slice(), &route.add].concat(),
+            set: [self.set.as_slice(), &route.set].concat(),
+            sequential_set: self.sequential_set || route.sequential_set,
+            remove: [self.remove.as_slice(), &route.remove].concat(),
+            host_rewrite: route
+                .host_rewrite
+                .clone()
+                .or_else(|| self.host_rewrite.clone()),
+            status: route.status.clone().or_else(|| self.status.clone()),
+            direct_response: route
+                .direct_response
+                .clone()
+                .or_else(|| self.direct_response.clone()),
+            copy_prefix: [self.copy_prefix.as_slice(), &route.copy_prefix].concat(),
+            body: route.body.clone().or_else(|| self.body.clone()),
+            passthrough: self.passthrough || route.passthrough,
+            metadata_from_body: [
+                self.metadata_from_body.as_slice(),
+                &route.metadata_from_body,
+            ]
+            .concat(),
+            extractors,
+            condition: route.condition.clone().or_else(|| self.condition.clone()),
+            methods: if route.methods.is_empty() {
+                self.methods.clone()
+            } else {
+                route.methods.clone()
+            },
+            clear_route_cache: self.clear_route_cache || route.clear_route_cache,
+            dynamic_metadata: [self.dynamic_metadata.as_slice(), &route.dynamic_metadata].concat(),
+            max_header_value_bytes: route.max_header_value_bytes.or(self.max_header_value_bytes),
+            oversized_header_value: if route.max_header_value_bytes.is_some() {
+                route.oversized_header_value
+            } else {
+                self.oversized_header_value
+            },
+            trailers: TrailersTransform {
+                set: [self.trailers.set.as_slice(), &route.trailers.set].concat(),
+                remove: [self.trailers.remove.as_slice(), &route.trailers.remove].concat(),
+            },
+            later_stages: [self.later_stages.as_slice(), &route.later_stages].concat(),
+        }
+    }
+
+    // Returns the body transform to apply, None if the body is passed through
+    pub fn body_transform(&self) -> Option<&BodyTransform> {
+        if self.passthrough {
+            return None;
+        }
+        self.body.as_ref()
+    }
+}
+
+// The local reply sent by a request transform, see directResponse. The body and the header
+// values are templates rendered with the context of the header templates, without the
+// body. A header value rendered empty is left out.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DirectResponse {
+    pub status: u32,
+    #[serde(default)]
+    pub body: String,
+    // e.g. `{"name": "location", "value": "/login?next={{ header(\":path\") }}"}`
+    #[serde(default)]
+    pub headers: Vec<NameValuePair>,
+}
+
+// The trailers are transformed when the stream ends with them, with the context of the
+// header templates but the body and the extractions. The envoy sdk can't add trailers to
+// a stream without any, so there is nothing to set then.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct TrailersTransform {
+    // Like the header set, a value rendered empty removes the trailer unless ifPresent is set
+    #[serde(default)]
+    pub set: Vec<NameValuePair>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+impl TrailersTransform {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.remove.is_empty()
     }
 }
 
-#[derive(Default, Clone, Deserialize)]
+// A body transform with parseAs AsJson and neither a value, a merge nor removeBody only
+// parses the body for the header templates, e.g. `{{ body_json().user.id }}`. The body is
+// buffered until it is complete and then forwarded unchanged.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct BodyTransform {
     #[serde(default, rename = "parseAs")]
     pub parse_as: BodyParseBehavior,
     #[serde(default)]
     pub value: String,
+    // A RFC 7386 json merge patch applied to the json body instead of re-rendering the
+    // whole body from `value`, e.g. `{"metadata": {"gateway": "{{ env(\"POD_NAME\") }}"}}`.
+    // The string values in the patch are templates, a null value deletes the key. A body
+    // that is not a json object, e.g. an array or not json at all, is left untouched.
+    #[serde(default)]
+    pub merge: Option<JsonValue>,
+    // When the body is rewritten, Content-Length is set to the new body length. When
+    // unset, Content-Length is removed instead and the body is sent chunked.
+    #[serde(
+        default = "default_recalculate_content_length",
+        rename = "recalculateContentLength"
+    )]
+    pub recalculate_content_length: bool,
+    // The Content-Type of the rendered body, e.g. `application/json` when a json body is
+    // rendered from a form. When unset, the Content-Type header is left as is.
+    #[serde(default, rename = "contentType")]
+    pub content_type: Option<String>,
+    // A body with a Content-Encoding is skipped by default, the transformation would
+    // otherwise parse or overwrite the compressed bytes. When set, a gzip or deflate body
+    // is decompressed before the transformation and sent on uncompressed. The other
+    // encodings are still skipped.
+    #[serde(default, rename = "decompressForTransform")]
+    pub decompress_for_transform: bool,
+    // With decompressForTransform, the transformed body is compressed back with its
+    // original encoding instead of being sent on uncompressed
+    #[serde(default, rename = "recompressAfterTransform")]
+    pub recompress_after_transform: bool,
+    // A body that is not valid UTF-8 is skipped by default so it's not mangled. When set,
+    // it is transformed anyway and is available to the templates as base64 through
+    // body_base64(). The rendered value is written out as is.
+    #[serde(default, rename = "treatBodyAsBytes")]
+    pub treat_body_as_bytes: bool,
+    // Drops the body, e.g. when a request is converted to a GET. Transfer-Encoding and
+    // Content-Type are removed and Content-Length is set to 0, or removed when
+    // recalculateContentLength is unset. Can't be used with value or merge.
+    #[serde(default, rename = "removeBody")]
+    pub remove_body: bool,
+    // When set, the body is transformed only when its Content-Type matches one of these
+    // media types, e.g. `application/json` or `application/*+json`. `*` matches any sequence
+    // and the parameters like `; charset=utf-8` are ignored. A body without Content-Type
+    // never matches. The header operations are applied either way.
+    #[serde(default, rename = "contentTypeMatches")]
+    pub content_type_matches: Vec<String>,
+    // A body that can't be parsed as json is rejected with a 400 by default. When set, the
+    // parsing error is only logged and the transformation goes on without the json fields,
+    // as if they were missing from the body.
+    #[serde(default, rename = "ignoreErrorOnParse")]
+    pub ignore_error_on_parse: bool,
+    // When set, the body is never buffered. value is rendered for each chunk as envoy
+    // receives it and the output replaces that chunk. The chunk is available as `chunk`,
+    // along with `chunk_index` and `end_of_stream`. The headers are transformed right away
+    // and Content-Length is removed. A chunk can end anywhere, e.g. in the middle of a json
+    // line. Only supported with parseAs AsString and a value.
+    #[serde(default)]
+    pub streaming: bool,
+    // With streaming, value is rendered for each line instead of each chunk, for the
+    // newline delimited bodies like server-sent events or json lines. `chunk` is then the
+    // line without its newline and `chunk_index` its position, and the newline is added
+    // back after the output. A line split over several chunks is held back until it is
+    // complete, a last line without a newline is rendered when the stream ends. Only
+    // supported on the response body.
+    #[serde(default)]
+    pub lines: bool,
+    // When set, the response body is transformed only for a status in this range, e.g.
+    // `{"min": 400, "max": 599}` to only rewrite the error bodies. The header operations
+    // are applied either way. Only supported on the response body.
+    #[serde(default, rename = "whenStatus")]
+    pub when_status: Option<StatusRange>,
+}
+
+fn default_recalculate_content_length() -> bool {
+    true
+}
+
+impl Default for BodyTransform {
+    fn default() -> Self {
+        BodyTransform {
+            parse_as: BodyParseBehavior::default(),
+            value: String::default(),
+            merge: None,
+            recalculate_content_length: default_recalculate_content_length(),
+            content_type: None,
+            decompress_for_transform: false,
+            recompress_after_transform: false,
+            treat_body_as_bytes: false,
+            remove_body: false,
+            content_type_matches: Vec::new(),
+            ignore_error_on_parse: false,
+            streaming: false,
+            lines: false,
+            when_status: None,
+        }
+    }
 }
 
 impl BodyTransform {
+    // Returns true if the body transform applies to a response with this :status
+    pub fn matches_status(&self, status: Option<&str>) -> bool {
+        let Some(StatusRange { min, max }) = self.when_status else {
+            return true;
+        };
+        status
+            .and_then(|s| s.trim().parse::<u16>().ok())
+            .is_some_and(|s| (min..=max).contains(&s))
+    }
+
     // This function is used to check if we need to do anything to the request/response at all
     // If parse_as is set to AsJson, even the value is empty (meaning we are not changing the body)
     // there is still works to do (parsing the body as json), so the json value can be used in
@@ -56,24 +896,269 @@ impl BodyTransform {
     // Further optimization can be done by also checking if there are any header transformation
     // at all, if not, we can return true if value is empty regardless of what parse_as is set to.
     pub fn is_empty(&self) -> bool {
-        if self.value.is_empty() && matches!(self.parse_as, BodyParseBehavior::AsString) {
+        if self.value.is_empty()
+            && self.merge.is_none()
+            && !self.remove_body
+            && matches!(self.parse_as, BodyParseBehavior::AsString)
+        {
             return true;
         }
         false
     }
 }
-#[derive(Default, Clone, Deserialize)]
+// A header to remove. A plain string is the name of the header, e.g. `x-internal`, and is
+// removed whether it was received or not. `{"prefix": "x-internal-"}` removes the headers
+// received starting with the prefix, ignoring the case, `{"glob": "x-*-debug"}` the ones
+// matching the glob, `*` matching any sequence of characters and ignoring the case too,
+// and `{"regex": "^x-(debug|trace)-"}` the ones whose lowercase name matches the regex.
+// Only the headers received are matched, not the ones set or added by the transform, and
+// never the pseudo headers like `:path`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderRemoval {
+    Name(String),
+    // Lowercased when the config is loaded, like the glob
+    Prefix(String),
+    Glob(String),
+    Regex(ConfigRegex),
+}
+
+impl HeaderRemoval {
+    // The name of the header to remove, None for a pattern
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            HeaderRemoval::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    // The name when it is a template, e.g. `x-{{ env("STAGE") }}-debug`, rendered before the
+    // header is removed
+    pub fn template(&self) -> Option<&str> {
+        self.name().filter(|name| has_template_syntax(name))
+    }
+
+    // Returns true if the pattern matches a received header, the names of the headers
+    // received being lowercase
+    pub fn matches(&self, name: &str) -> bool {
+        if name.starts_with(':') {
+            return false;
+        }
+        match self {
+            HeaderRemoval::Name(_) => false,
+            HeaderRemoval::Prefix(prefix) => name.starts_with(prefix.as_str()),
+            HeaderRemoval::Glob(pattern) => glob_match(pattern, name),
+            HeaderRemoval::Regex(regex) => regex.regex.is_match(name),
+        }
+    }
+}
+
+// Matches a name against a glob pattern, `*` matches any sequence, e.g. a header name or a
+// media type
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always returns at least one part
+    let first = parts.next().unwrap();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // no wildcard, the whole name has to match
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+impl<'de> Deserialize<'de> for HeaderRemoval {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawHeaderRemoval {
+            Name(String),
+            Prefix { prefix: String },
+            Glob { glob: String },
+            Regex { regex: String },
+        }
+        Ok(match RawHeaderRemoval::deserialize(deserializer)? {
+            RawHeaderRemoval::Name(name) => HeaderRemoval::Name(name),
+            RawHeaderRemoval::Prefix { prefix } => HeaderRemoval::Prefix(prefix.to_lowercase()),
+            RawHeaderRemoval::Glob { glob } => HeaderRemoval::Glob(glob.to_lowercase()),
+            RawHeaderRemoval::Regex { regex } => {
+                HeaderRemoval::Regex(ConfigRegex::new(&regex).map_err(serde::de::Error::custom)?)
+            }
+        })
+    }
+}
+
+// An inclusive range of status codes
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct StatusRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
 pub struct NameValuePair {
     pub name: String,
     #[serde(default)]
     pub value: String,
+    // Overrides the transform maxHeaderValueBytes for this header
+    #[serde(default, rename = "maxValueBytes")]
+    pub max_value_bytes: Option<usize>,
+    // The value is written as is instead of being rendered, so `{{ }}` is kept in the
+    // header. The values without any template delimiter are marked literal at load time,
+    // see mark_literal_values.
+    #[serde(default)]
+    pub literal: bool,
+    // Only for set, a value rendering empty leaves the header untouched instead of
+    // removing it
+    #[serde(default, rename = "ifPresent")]
+    pub if_present: bool,
+}
+
+impl NameValuePair {
+    // Returns true if the value has a jinja delimiter, without one it renders as is
+    pub fn has_template_syntax(&self) -> bool {
+        has_template_syntax(&self.value)
+    }
+}
+
+fn has_template_syntax(text: &str) -> bool {
+    ["{{", "{%", "{#"]
+        .iter()
+        .any(|delimiter| text.contains(delimiter))
+}
+
+// Which value of a repeated header the templates see, see duplicateHeaders
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateHeaderPolicy {
+    // The last value wins, as it always did
+    #[default]
+    Last,
+    // The first value wins, that is the first of several x-forwarded-for headers
+    First,
+}
+
+// What is done with a rendered header value over the maximum size
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizedValuePolicy {
+    // The value is cut at the maximum size, on a UTF-8 character boundary
+    #[default]
+    Truncate,
+    // Same as Truncate, with the value ending with `...` to show it was cut
+    Ellipsis,
+    // The value is dropped as if it had rendered empty, so a set removes the header and
+    // an add is skipped
+    Drop,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct CopyPrefix {
+    // Matched case-insensitively, an empty prefix matches all the headers
+    #[serde(rename = "fromPrefix")]
+    pub from_prefix: String,
+    #[serde(rename = "toPrefix")]
+    pub to_prefix: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Extractor {
+    // The header to extract from, the pseudo headers like `:path` can be used
+    pub header: String,
+    // Like the C++ extractors, in the EXTRACT mode the regex has to match the whole header
+    // value. An extractor that doesn't match, or with a missing header, extracts an empty
+    // string.
+    pub regex: ConfigRegex,
+    // The capture group to extract, 0 being the whole match. Only used in the EXTRACT mode.
+    #[serde(default)]
+    pub subgroup: usize,
+    #[serde(default)]
+    pub mode: ExtractionMode,
+    // The text the matches are replaced with in the replace modes. It can reference the
+    // capture groups as `\1`.
+    #[serde(default, rename = "replacementText")]
+    pub replacement_text: Option<String>,
 }
 
-#[derive(Default, Clone, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ExtractionMode {
+    // Extracts the subgroup, the header is left as is
+    #[default]
+    Extract,
+    // Replaces the first match in the header with the replacement text. The header is
+    // rewritten before the templates are rendered, so they see the new value, and the
+    // extraction is the new header value.
+    SingleReplace,
+    // Same as SingleReplace for all the matches
+    ReplaceAll,
+}
+
+// A regex from the config, compiled once when the config is loaded
+#[derive(Debug, Clone)]
+pub struct ConfigRegex {
+    // Matches anywhere in the value, e.g. for the extractor replace modes
+    pub regex: regex::Regex,
+    // Only matches the whole value
+    pub full_match: regex::Regex,
+}
+
+impl PartialEq for ConfigRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.regex.as_str() == other.regex.as_str()
+    }
+}
+
+impl ConfigRegex {
+    pub fn new(regex: &str) -> Result<Self, regex::Error> {
+        Ok(ConfigRegex {
+            full_match: regex::Regex::new(&format!("^(?:{regex})$"))?,
+            regex: regex::Regex::new(regex)?,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigRegex {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let regex = String::deserialize(deserializer)?;
+        ConfigRegex::new(&regex).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct DynamicMetadata {
+    pub namespace: String,
+    pub key: String,
+    // A template, e.g. `{{ extraction("user_id") }}`
+    #[serde(default)]
+    pub value: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct MetadataFromBody {
+    pub namespace: String,
+    pub key: String,
+    // A RFC 6901 json pointer, e.g. `/user/id`
+    #[serde(rename = "jsonPointer")]
+    pub json_pointer: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
 pub enum BodyParseBehavior {
     #[default]
     AsString,
     AsJson,
+    // The application/x-www-form-urlencoded fields are available to the templates as
+    // `form`, e.g. `{{ form.client_id }}`
+    AsFormUrlEncoded,
 }
 
 pub trait TransformationOps {
@@ -83,14 +1168,53 @@ pub trait TransformationOps {
     fn add_response_header(&mut self, key: &str, value: &[u8]) -> bool;
     fn set_response_header(&mut self, key: &str, value: &[u8]) -> bool;
     fn remove_response_header(&mut self, key: &str) -> bool;
+    fn set_request_trailer(&mut self, key: &str, value: &[u8]) -> bool;
+    fn remove_request_trailer(&mut self, key: &str) -> bool;
+    fn set_response_trailer(&mut self, key: &str, value: &[u8]) -> bool;
+    fn remove_response_trailer(&mut self, key: &str) -> bool;
     fn parse_request_json_body(&mut self) -> Result<JsonValue>;
     fn get_request_body(&mut self) -> Vec<u8>;
     fn drain_request_body(&mut self, number_of_bytes: usize) -> bool;
     fn append_request_body(&mut self, data: &[u8]) -> bool;
+    // Replaces the whole request body. The envoy sdk drain function would drain all the
+    // bytes if the number passed in is greater than the content length.
+    fn set_request_body(&mut self, data: &[u8]) -> bool {
+        self.drain_request_body(usize::MAX);
+        self.append_request_body(data)
+    }
     fn parse_response_json_body(&mut self) -> Result<JsonValue>;
     fn get_response_body(&mut self) -> Vec<u8>;
     fn drain_response_body(&mut self, number_of_bytes: usize) -> bool;
     fn append_response_body(&mut self, data: &[u8]) -> bool;
+    fn set_response_body(&mut self, data: &[u8]) -> bool {
+        self.drain_response_body(usize::MAX);
+        self.append_response_body(data)
+    }
+    // Stops the filter chain and replies to the downstream with the given status, headers
+    // and body
+    fn send_local_reply(&mut self, status_code: u32, headers: &[(String, String)], body: &[u8]);
+    fn set_dynamic_metadata(&mut self, namespace: &str, key: &str, value: &str) -> bool;
+    // Picks the route again, after the request headers it depends on have been changed
+    fn clear_route_cache(&mut self);
+    fn log_debug(&self, _msg: &str) {}
+    // Called for each outcome of the transformation, so it can be counted
+    fn increment_stat(&mut self, _stat: TransformationStat) {}
+    // Called with the time it took to render each header template
+    fn record_render_duration(&mut self, _duration: Duration) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransformationStat {
+    // A request or response transform was applied, that is its condition held
+    Transformation,
+    // A header was set or added
+    HeaderSet,
+    HeaderRemoved,
+    // A header or body template failed to render
+    RenderError,
+    // The body was replaced, merged with a patch or removed. A streamed body is counted
+    // once, not once per chunk.
+    BodyTransform,
 }
 
 #[derive(thiserror::Error, Debug)]
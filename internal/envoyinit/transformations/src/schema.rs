@@ -0,0 +1,251 @@
+use serde::de::value::StrDeserializer;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use serde_json::Value as JsonValue;
+use std::cell::{Cell, RefCell};
+
+// Returns the json path of every field of the config that T doesn't know, e.g. `request.sett`
+// or `request.set[0].nmae`, like serde's deny_unknown_fields would refuse them but listing
+// all of them. The value is deserialized as T and the field names serde passes along for
+// each struct are compared to the object keys. The fields of the enum variants and of the
+// types deserialized through a buffer, e.g. the untagged enums or a flattened struct, are
+// not checked.
+pub fn unknown_fields<T: DeserializeOwned>(value: &JsonValue) -> Vec<String> {
+    let unknown = RefCell::new(Vec::new());
+    // a config that doesn't deserialize is reported when it's parsed, not here
+    let _ = T::deserialize(Tracked {
+        value,
+        path: String::new(),
+        unknown: &unknown,
+    });
+    unknown.into_inner()
+}
+
+// Returns the names of the fields serde expects for the struct T, e.g. to deserialize it
+// from a Deserializer::deserialize_struct() call of its own
+pub fn struct_fields<T: DeserializeOwned>() -> &'static [&'static str] {
+    let fields = Cell::new(&[][..]);
+    let _ = T::deserialize(FieldsProbe(&fields));
+    fields.get()
+}
+
+// A deserializer only recording the fields of the struct asked for, it never returns a value
+struct FieldsProbe<'a>(&'a Cell<&'static [&'static str]>);
+
+impl<'de> de::Deserializer<'de> for FieldsProbe<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.set(fields);
+        Err(de::Error::custom("probed"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+// A deserializer over a json value keeping track of where it is in the config
+struct Tracked<'a> {
+    value: &'a JsonValue,
+    path: String,
+    unknown: &'a RefCell<Vec<String>>,
+}
+
+impl<'a> Tracked<'a> {
+    fn field(&self, key: &str, value: &'a JsonValue) -> Self {
+        let path = if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{key}", self.path)
+        };
+        Tracked {
+            value,
+            path,
+            unknown: self.unknown,
+        }
+    }
+
+    fn element(&self, index: usize, value: &'a JsonValue) -> Self {
+        Tracked {
+            value,
+            path: format!("{}[{index}]", self.path),
+            unknown: self.unknown,
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Tracked<'_> {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            JsonValue::Array(items) => visitor.visit_seq(TrackedSeq {
+                parent: &self,
+                items: items.iter().enumerate(),
+            }),
+            JsonValue::Object(fields) => visitor.visit_map(TrackedMap {
+                parent: &self,
+                fields: fields.iter(),
+                pending: None,
+            }),
+            value => value.clone().deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            JsonValue::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        if let JsonValue::Object(object) = self.value {
+            for key in object.keys().filter(|key| !fields.contains(&key.as_str())) {
+                let path = self.field(key, &JsonValue::Null).path;
+                self.unknown.borrow_mut().push(path);
+            }
+        }
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.clone().deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map identifier ignored_any
+    }
+}
+
+struct TrackedSeq<'p, 'a, I> {
+    parent: &'p Tracked<'a>,
+    items: I,
+}
+
+impl<'de, 'a, I: Iterator<Item = (usize, &'a JsonValue)>> SeqAccess<'de> for TrackedSeq<'_, 'a, I> {
+    type Error = serde_json::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        match self.items.next() {
+            Some((index, value)) => seed
+                .deserialize(self.parent.element(index, value))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+struct TrackedMap<'p, 'a, I> {
+    parent: &'p Tracked<'a>,
+    fields: I,
+    pending: Option<(&'a String, &'a JsonValue)>,
+}
+
+impl<'de, 'a, I: Iterator<Item = (&'a String, &'a JsonValue)>> MapAccess<'de>
+    for TrackedMap<'_, 'a, I>
+{
+    type Error = serde_json::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some((key, value)) = self.fields.next() else {
+            return Ok(None);
+        };
+        self.pending = Some((key, value));
+        seed.deserialize(StrDeserializer::new(key)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (key, value) = self
+            .pending
+            .take()
+            .ok_or_else(|| de::Error::custom("value requested before its key"))?;
+        seed.deserialize(self.parent.field(key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LocalTransformationConfig;
+    use serde_json::json;
+
+    #[test]
+    fn test_unknown_fields() {
+        let unknown = |config: JsonValue| unknown_fields::<LocalTransformationConfig>(&config);
+
+        assert!(unknown(json!({
+            "request": {
+                "set": [ { "name": "x-foo", "value": "foo", "maxValueBytes": 10 } ],
+                "body": { "parseAs": "AsJson", "value": "{}" }
+            },
+            "vars": { "anything": "goes" },
+            "strictTemplates": true
+        }))
+        .is_empty());
+
+        assert_eq!(
+            unknown(json!({
+                "requset": {},
+                "response": {
+                    "set": [
+                        { "name": "x-foo", "value": "foo" },
+                        { "nmae": "x-bar", "value": "bar" }
+                    ],
+                    "body": { "parseAs": "AsJson", "valeu": "{}" }
+                }
+            })),
+            vec!["requset", "response.body.valeu", "response.set[1].nmae"]
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields_invalid_config() {
+        // the type errors are left to the config parsing
+        let config = json!({ "maxBufferedBodyBytes": "lots", "extra": 1 });
+        assert_eq!(
+            unknown_fields::<LocalTransformationConfig>(&config),
+            vec!["extra"]
+        );
+    }
+}
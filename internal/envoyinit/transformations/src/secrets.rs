@@ -0,0 +1,141 @@
+use crate::SecretSource;
+use anyhow::{Context, Result};
+use serde_json::Value as JsonValue;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+// What a secret value is replaced with in the logs, the errors and the context() dump
+pub const REDACTED: &str = "[redacted]";
+
+// The secrets of a config, read from their files once when the config is loaded. The
+// templates only see them through secret(), and the values never show up in a debug
+// print of this struct.
+#[derive(Default, Clone)]
+pub struct Secrets {
+    values: HashMap<String, String>,
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.values.keys().collect();
+        names.sort();
+        f.debug_struct("Secrets").field("names", &names).finish()
+    }
+}
+
+impl Secrets {
+    // Reads each secret from its file, a trailing newline is not part of the value. Any
+    // file that can't be read fails the whole config.
+    pub fn load(sources: &HashMap<String, SecretSource>) -> Result<Self> {
+        let mut values = HashMap::new();
+        for (name, source) in sources {
+            let value = std::fs::read_to_string(&source.file)
+                .with_context(|| format!("secret {name}: error reading {}", source.file))?;
+            let value = value.strip_suffix('\n').unwrap_or(&value);
+            let value = value.strip_suffix('\r').unwrap_or(value);
+            values.insert(name.clone(), value.to_string());
+        }
+        Ok(Secrets { values })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // Returns these secrets with the ones of a route config, the route ones win
+    pub fn merge(&self, route: &Secrets) -> Secrets {
+        let mut values = self.values.clone();
+        values.extend(route.values.clone());
+        Secrets { values }
+    }
+
+    // Replaces every secret value found in the text, e.g. an error message echoing a
+    // rendered value. The longer values are replaced first so a secret containing
+    // another one is fully redacted.
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut values: Vec<&str> = self
+            .values
+            .values()
+            .map(String::as_str)
+            .filter(|value| !value.is_empty() && text.contains(value))
+            .collect();
+        if values.is_empty() {
+            return Cow::Borrowed(text);
+        }
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        let mut redacted = text.to_string();
+        for value in values {
+            redacted = redacted.replace(value, REDACTED);
+        }
+        Cow::Owned(redacted)
+    }
+
+    // Redacts the strings of a json value, the keys included
+    pub fn redact_json(&self, value: JsonValue) -> JsonValue {
+        match value {
+            JsonValue::String(s) => JsonValue::String(self.redact(&s).into_owned()),
+            JsonValue::Array(items) => {
+                JsonValue::Array(items.into_iter().map(|v| self.redact_json(v)).collect())
+            }
+            JsonValue::Object(map) => JsonValue::Object(
+                map.into_iter()
+                    .map(|(k, v)| (self.redact(&k).into_owned(), self.redact_json(v)))
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn secrets(values: &[(&str, &str)]) -> Secrets {
+        Secrets {
+            values: values
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_redact() {
+        let secrets = secrets(&[("short", "abc"), ("long", "abcdef"), ("empty", "")]);
+        assert_eq!(
+            secrets.redact("key=abcdef, other=abc"),
+            "key=[redacted], other=[redacted]"
+        );
+        assert!(matches!(secrets.redact("nothing here"), Cow::Borrowed(_)));
+        assert_eq!(
+            secrets.redact_json(json!({"abc": ["x-abcdef", 1], "n": null})),
+            json!({"[redacted]": ["x-[redacted]", 1], "n": null})
+        );
+        assert_eq!(
+            format!("{secrets:?}"),
+            r#"Secrets { names: ["empty", "long", "short"] }"#
+        );
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let sources = HashMap::from([(
+            "api_key".to_string(),
+            SecretSource {
+                file: "/nonexistent/api_key".to_string(),
+            },
+        )]);
+        let err = format!("{:#}", Secrets::load(&sources).unwrap_err());
+        assert!(
+            err.starts_with("secret api_key: error reading /nonexistent/api_key"),
+            "{err}"
+        );
+    }
+}